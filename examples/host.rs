@@ -7,7 +7,7 @@ use std::process::exit;
 use std::time::Duration;
 
 use rotor::void::{Void, unreachable};
-use argparse::{ArgumentParser, Store, List, ParseOption};
+use argparse::{ArgumentParser, Store, StoreTrue, List, ParseOption};
 use rotor::{Machine, EventSet, Scope, Response};
 use rotor_dns::{CacheEntry, Query, Answer};
 use rotor_tools::loop_ext::{LoopExt};
@@ -49,6 +49,7 @@ fn main() {
     let mut servers = vec![];
     let mut timeout = None;
     let mut attempts = None;
+    let mut ipv6 = false;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("
@@ -62,6 +63,8 @@ fn main() {
             Override number of attempts");
         ap.refer(&mut timeout).add_option(&["--timeout-ms"], ParseOption, "
             Override the network timeout. In milliseconds.");
+        ap.refer(&mut ipv6).add_option(&["-6", "--ipv6"], StoreTrue, "
+            Resolve IPv6 addresses (AAAA records) instead of IPv4 ones");
         ap.refer(&mut host).add_argument("name", Store, "
             Hostname to resolve");
         ap.parse_args_or_exit();
@@ -70,6 +73,8 @@ fn main() {
     let mut cfg = rotor_dns::Config::system().unwrap();
     if servers.len() > 0 {
         cfg.nameservers = servers;
+        // Don't replace them when resolv.conf changes
+        cfg.resolv_conf = None;
     }
     attempts.map(|x| cfg.attempts = x);
     timeout.map(|x| cfg.timeout = Duration::from_millis(x));
@@ -79,20 +84,31 @@ fn main() {
     let mut loop_inst = loop_creator.instantiate(Context);
     let mut query = None;
     loop_inst.add_machine_with(|scope| {
-        query = Some(resolver.query::<Scope<Context>>(
-            Query::LookupIpv4(host), scope).unwrap());
+        let q = if ipv6 {
+            Query::LookupIpv6(host)
+        } else {
+            Query::LookupIpv4(host)
+        };
+        query = Some(resolver.query::<Scope<Context>>(q, scope).unwrap());
         Response::ok(Composed::Shut(Shutter))
     }).unwrap();
     loop_inst.run().unwrap();
     let qb = query.unwrap();
     let query = qb.lock().unwrap();
     let entry = query.as_ref().map(|x| &**x);
-    if let Some(&CacheEntry { value: Answer::Ipv4(ref ips), .. }) = entry {
-        for ip in ips {
-            println!("{}", ip);
+    match entry {
+        Some(&CacheEntry { value: Answer::Ipv4(ref ips), .. }) => {
+            for ip in ips {
+                println!("{}", ip);
+            }
+            exit(0);
         }
-        exit(0);
-    } else {
-        exit(1);
+        Some(&CacheEntry { value: Answer::Ipv6(ref ips), .. }) => {
+            for ip in ips {
+                println!("{}", ip);
+            }
+            exit(0);
+        }
+        _ => exit(1),
     }
 }
@@ -0,0 +1,138 @@
+use std::collections::{HashMap, BTreeMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rotor::Time;
+
+use {Query, CacheEntry, DnsMachine};
+
+/// How often expired entries are removed from the cache (seconds)
+const SWEEP_INTERVAL: u64 = 60;
+/// Seconds the stale answer is valid (RFC 8767, section 4)
+const STALE_TTL: u64 = 30;
+
+
+/// Cache of answers limited by the number of entries
+///
+/// When cache is full the least recently used entry is evicted.
+/// Expired entries are kept for `keep_stale` time to be used when
+/// nameservers are unreachable.
+#[derive(Debug)]
+pub struct Cache {
+    entries: HashMap<Query, (Arc<CacheEntry>, u64)>,
+    /// Queries by the value of `counter` when they were used last time
+    recent: BTreeMap<u64, Query>,
+    counter: u64,
+    limit: usize,
+    keep_stale: Duration,
+}
+
+impl Cache {
+    pub fn new(limit: usize, keep_stale: Duration) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            recent: BTreeMap::new(),
+            counter: 0,
+            limit: limit,
+            keep_stale: keep_stale,
+        }
+    }
+    /// Returns the entry if it's not expired yet and marks it used
+    pub fn get(&mut self, query: &Query, now: Time)
+        -> Option<Arc<CacheEntry>>
+    {
+        let (expired, outdated) = match self.entries.get(query) {
+            Some(&(ref entry, _)) => {
+                (now > entry.expire, now > entry.expire + self.keep_stale)
+            }
+            None => return None,
+        };
+        if outdated {
+            self.remove(query);
+        }
+        if expired {
+            return None;
+        }
+        self.counter += 1;
+        let &mut (ref entry, ref mut used) =
+            self.entries.get_mut(query).unwrap();
+        self.recent.remove(&*used);
+        *used = self.counter;
+        self.recent.insert(self.counter, query.clone());
+        Some(entry.clone())
+    }
+    /// Returns the entry even if it's expired, unless it's too old
+    pub fn get_stale(&self, query: &Query, now: Time)
+        -> Option<Arc<CacheEntry>>
+    {
+        match self.entries.get(query) {
+            Some(&(ref entry, _)) if now <= entry.expire + self.keep_stale
+            => Some(entry.clone()),
+            _ => None,
+        }
+    }
+    pub fn insert(&mut self, query: Query, entry: Arc<CacheEntry>) {
+        if self.limit == 0 {
+            return;
+        }
+        self.remove(&query);
+        while self.entries.len() >= self.limit {
+            let oldest = *self.recent.keys().next().unwrap();
+            let query = self.recent.remove(&oldest).unwrap();
+            self.entries.remove(&query);
+        }
+        self.counter += 1;
+        self.recent.insert(self.counter, query.clone());
+        self.entries.insert(query, (entry, self.counter));
+    }
+    pub fn remove(&mut self, query: &Query) {
+        if let Some((_, used)) = self.entries.remove(query) {
+            self.recent.remove(&used);
+        }
+    }
+    /// Removes all expired entries
+    pub fn sweep(&mut self, now: Time) {
+        let expired = self.entries.iter()
+            .filter(|&(_, &(ref entry, _))| {
+                now > entry.expire + self.keep_stale
+            })
+            .map(|(query, _)| query.clone())
+            .collect::<Vec<_>>();
+        for query in expired {
+            self.remove(&query);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recent.clear();
+    }
+}
+
+impl DnsMachine {
+    /// Returns the expired answer to the query, flagged as stale, if
+    /// serving stale answers is enabled
+    pub fn stale_entry(&self, query: &Query, now: Time)
+        -> Option<Arc<CacheEntry>>
+    {
+        if self.config.serve_stale.is_none() {
+            return None;
+        }
+        self.cache.get_stale(query, now).map(|entry| Arc::new(CacheEntry {
+            value: entry.value.clone(),
+            expire: now + Duration::new(STALE_TTL, 0),
+            canonical_name: entry.canonical_name.clone(),
+            name: entry.name.clone(),
+            stale: true,
+        }))
+    }
+    /// Removes expired entries from the cache from time to time
+    ///
+    /// Returns when it should be called next time
+    pub fn sweep_cache(&mut self, now: Time) -> Time {
+        if self.next_sweep <= now {
+            self.cache.sweep(now);
+            self.next_sweep = now + Duration::new(SWEEP_INTERVAL, 0);
+        }
+        self.next_sweep
+    }
+}
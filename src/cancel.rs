@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+
+use {DnsMachine, CacheEntry, Waiter, Id};
+
+
+/// The slot returned by `Resolver::query`
+pub type Slot = Arc<Mutex<Option<Arc<CacheEntry>>>>;
+
+fn same_slot(a: &Slot, b: &Slot) -> bool {
+    &**a as *const _ == &**b as *const _
+}
+
+impl DnsMachine {
+    /// Stops delivering the result to the slot
+    ///
+    /// Searches, host lookups and requests left without waiters are
+    /// dropped too.
+    pub fn cancel(&mut self, slot: &Slot) {
+        let is_slot = |w: &Waiter| match *w {
+            Waiter::Machine(ref x, _) => same_slot(x, slot),
+            _ => false,
+        };
+        for search in self.searches.values_mut() {
+            search.notifiers.retain(|w| !is_slot(w));
+        }
+        let searches = self.searches.iter()
+            .filter(|&(_, search)| search.notifiers.is_empty())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &searches {
+            self.searches.remove(key);
+        }
+        let is_search = |w: &Waiter| match *w {
+            Waiter::Search(ref query, options) => {
+                searches.iter().any(|k| k.0 == *query && k.1 == options)
+            }
+            _ => false,
+        };
+
+        for lookup in self.host_lookups.values_mut() {
+            lookup.notifiers.retain(|w| !is_slot(w) && !is_search(w));
+        }
+        let lookups = self.host_lookups.iter()
+            .filter(|&(_, lookup)| lookup.notifiers.is_empty())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &lookups {
+            self.host_lookups.remove(key);
+        }
+        let is_lookup = |w: &Waiter| match *w {
+            Waiter::HostIpv4(ref name, options)
+            | Waiter::HostIpv6(ref name, options) => {
+                lookups.iter().any(|k| k.0 == *name && k.1 == options)
+            }
+            _ => false,
+        };
+
+        for req in self.running.values_mut() {
+            req.notifiers.retain(|w| {
+                !is_slot(w) && !is_search(w) && !is_lookup(w)
+            });
+        }
+        let requests = self.running.iter()
+            .filter(|&(_, req)| req.notifiers.is_empty())
+            .map(|(&id, _)| id)
+            .collect::<Vec<Id>>();
+        for id in requests {
+            let mut req = self.running.remove(&id).unwrap();
+            // TCP socket is deregistered when it's closed
+            self.clear_hedges(&mut req);
+            self.queued.remove(&req.key());
+            self.pending_tcp.retain(|&x| x != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Config, Query};
+    use mock::{Mock, Server, reply, result};
+
+    #[test]
+    fn cancel_last_waiter() {
+        let server = Server::new();
+        let config = Config::new(vec![server.address()]);
+        let timeout = config.timeout;
+        let mut mock = Mock::new(config);
+        let query = Query::LookupIpv4("example.com".into());
+        let first = mock.query(query.clone());
+        let second = mock.query(query.clone());
+        let packet = server.recv().unwrap();
+
+        mock.cancel(&first);
+        assert_eq!(mock.machine.lock().unwrap().running.len(), 1);
+        mock.cancel(&second);
+        {
+            let machine = mock.machine.lock().unwrap();
+            assert!(machine.running.is_empty());
+            assert!(machine.queued.is_empty());
+        }
+
+        server.send(&reply(&packet, &[]), &mock);
+        mock.ready();
+        mock.sleep(timeout * 10);
+        mock.timeout();
+        assert!(result(&first).is_none());
+        assert!(result(&second).is_none());
+        assert_eq!(mock.client_wakeups(), 0);
+        assert!(server.recv().is_none());
+    }
+}
@@ -1,12 +1,24 @@
 use std::io;
 use std::io::Read;
 use std::fs::File;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use resolv_conf;
 
+/// Timeout of the first attempt (seconds), same as in glibc
+pub const DEFAULT_TIMEOUT: u64 = 5;
+/// Number of attempts for each request, same as in glibc
+pub const DEFAULT_ATTEMPTS: u32 = 2;
+/// The payload size that avoids IP fragmentation on most networks
+pub const DEFAULT_PAYLOAD_SIZE: u16 = 1232;
+/// How often system files are checked for changes by default
+pub const DEFAULT_RELOAD_INTERVAL: u64 = 5;
+/// Number of answers kept in the cache by default
+pub const DEFAULT_CACHE_SIZE: usize = 10000;
+/// Default upper bound for TTL of cached answers (RFC 8767, section 4)
+pub const DEFAULT_MAX_TTL: u64 = 604800;
 
 quick_error! {
     #[derive(Debug)]
@@ -24,27 +36,278 @@ quick_error! {
 }
 
 
+/// Strategy of choosing nameserver for the request
+///
+/// On retry the next nameserver in the list is used, except for
+/// `LowestRtt` which picks the best one of the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerSelection {
+    /// Always start with the first nameserver (default in glibc)
+    Sequential,
+    /// Start with the next nameserver for each request (`rotate` option)
+    Rotate,
+    /// Start with the nameserver that has lowest smoothed response time
+    LowestRtt,
+}
+
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub nameservers: Vec<SocketAddr>,
+    /// Timeout of the first round of attempts, it's doubled on each next
+    /// round through the nameserver list
     pub timeout: Duration,
     pub attempts: u32,
+    /// Overall time limit for the query including all retries
+    pub query_timeout: Option<Duration>,
+    /// Domains appended to relative names (from `search` and `domain`)
+    pub search: Vec<String>,
+    /// Names with fewer dots are tried with search domains first
+    pub ndots: u32,
+    /// Static host names table consulted before querying nameservers
+    pub hosts_file: Option<PathBuf>,
+    /// UDP payload size advertised using EDNS(0), `None` disables EDNS
+    ///
+    /// Enabled by default. Unlike in glibc, missing `edns0` option in
+    /// resolv.conf doesn't disable it.
+    pub edns_payload_size: Option<u16>,
+    /// The resolv.conf to re-read settings from when it changes
+    pub resolv_conf: Option<PathBuf>,
+    /// How often `resolv_conf` and `hosts_file` are checked for changes,
+    /// `None` disables reloading
+    pub reload_interval: Option<Duration>,
+    /// Maximum number of cached answers, least recently used ones are
+    /// evicted first, zero disables caching
+    pub cache_size: usize,
+    /// Answers with smaller TTL are cached for this time anyway
+    pub min_ttl: Duration,
+    /// Answers with larger TTL are cached only for this time
+    pub max_ttl: Duration,
+    /// How long expired answers are kept to be returned when nameservers
+    /// are unreachable (RFC 8767), `None` disables serving stale answers
+    pub serve_stale: Option<Duration>,
+    /// How nameserver is chosen for each request
+    pub selection: ServerSelection,
+    /// Number of nameservers each request is sent to simultaneously,
+    /// the first valid answer is used
+    pub parallel: usize,
+    /// Send request to one more nameserver if there is no answer after
+    /// this delay, it should be less than `timeout` to be useful
+    pub hedge_delay: Option<Duration>,
+    /// Send all requests over TCP
+    pub use_vc: bool,
+    /// Don't send A and AAAA requests of `LookupHost` in parallel
+    pub single_request: bool,
+    /// Don't send AAAA requests at all
+    pub no_aaaa: bool,
+    /// Query IPv6 addresses first and return IPv4 addresses as
+    /// IPv4-mapped IPv6 ones in `LookupHost`
+    pub inet6: bool,
 }
 
 
 impl Config {
+    /// Configuration with the given nameservers and glibc defaults
+    ///
+    /// Neither hosts file nor resolv.conf are used.
+    pub fn new(nameservers: Vec<SocketAddr>) -> Config {
+        Config {
+            nameservers: nameservers,
+            timeout: Duration::new(DEFAULT_TIMEOUT, 0),
+            attempts: DEFAULT_ATTEMPTS,
+            query_timeout: None,
+            search: Vec::new(),
+            ndots: 1,
+            hosts_file: None,
+            edns_payload_size: Some(DEFAULT_PAYLOAD_SIZE),
+            resolv_conf: None,
+            reload_interval: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+            min_ttl: Duration::new(0, 0),
+            max_ttl: Duration::new(DEFAULT_MAX_TTL, 0),
+            serve_stale: None,
+            selection: ServerSelection::Sequential,
+            parallel: 1,
+            hedge_delay: None,
+            use_vc: false,
+            single_request: false,
+            no_aaaa: false,
+            inet6: false,
+        }
+    }
+    /// Configuration read from `/etc/resolv.conf` using `/etc/hosts`
+    ///
+    /// Both files are checked for changes every
+    /// `DEFAULT_RELOAD_INTERVAL` seconds, set `reload_interval` to `None`
+    /// to disable that.
     pub fn system() -> Result<Config, SystemConfigError> {
+        let path = Path::new("/etc/resolv.conf");
+        let mut config = Config::new(Vec::new());
+        config.hosts_file = Some("/etc/hosts".into());
+        config.resolv_conf = Some(path.to_path_buf());
+        config.reload_interval = Some(
+            Duration::new(DEFAULT_RELOAD_INTERVAL, 0));
+        try!(config.read_resolv_conf(path));
+        Ok(config)
+    }
+    /// Updates settings that are configured in resolv.conf
+    ///
+    /// Nameservers and search domains are always replaced. Other settings
+    /// are changed only when the file has a corresponding option, so
+    /// flags like `rotate` or `use-vc` are turned on by the file but never
+    /// turned off. On error config is not changed.
+    pub fn read_resolv_conf(&mut self, path: &Path)
+        -> Result<(), SystemConfigError>
+    {
         use self::SystemConfigError::*;
         let mut buf = Vec::with_capacity(512);
-        try!(File::open("/etc/resolv.conf")
+        try!(File::open(path)
             .and_then(|mut f| f.read_to_end(&mut buf))
-            .map_err(|e| FileError("/etc/resolv.conf".into(), e)));
-        let cfg = try!(resolv_conf::Config::parse(&buf));
-        Ok(Config {
-            nameservers: cfg.nameservers.iter()
-                .map(|ns| SocketAddr::new(*ns, 53))
-                .collect(),
-            timeout: Duration::new(cfg.timeout.into(), 0),
-            attempts: cfg.attempts.into(),
-        })
+            .map_err(|e| FileError(path.to_path_buf(), e)));
+        self.parse_resolv_conf(&buf)
+    }
+    /// Same as `read_resolv_conf` but takes contents of the file
+    pub fn parse_resolv_conf(&mut self, buf: &[u8])
+        -> Result<(), SystemConfigError>
+    {
+        let cfg = try!(resolv_conf::Config::parse(buf));
+        let options = option_names(buf);
+        let has = |name: &str| options.iter().any(|x| x == name);
+        self.nameservers = cfg.nameservers.iter()
+            .map(|ns| SocketAddr::new(*ns, 53))
+            .collect();
+        if self.nameservers.len() == 0 {
+            // Same default as in glibc
+            self.nameservers.push(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 53));
+        }
+        // resolv-conf puts `domain` into the search list too
+        self.search = cfg.search.clone();
+        if has("timeout") {
+            self.timeout = Duration::new(cfg.timeout.into(), 0);
+        }
+        if has("attempts") {
+            self.attempts = cfg.attempts.into();
+        }
+        if has("ndots") {
+            self.ndots = cfg.ndots.into();
+        }
+        if cfg.edns0 && self.edns_payload_size.is_none() {
+            self.edns_payload_size = Some(DEFAULT_PAYLOAD_SIZE);
+        }
+        if cfg.rotate {
+            self.selection = ServerSelection::Rotate;
+        }
+        self.use_vc |= cfg.use_vc;
+        self.single_request |= cfg.single_request;
+        // Not supported by resolv-conf, glibc has it since 2.36
+        if has("no-aaaa") {
+            self.no_aaaa = true;
+        }
+        self.inet6 |= cfg.inet6;
+        Ok(())
+    }
+}
+
+/// Names of the options in resolv.conf, without values (`ndots:2` is
+/// returned as `ndots`)
+fn option_names(data: &[u8]) -> Vec<String> {
+    let mut result = Vec::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("options") {
+            continue;
+        }
+        for word in words {
+            if word.starts_with('#') || word.starts_with(';') {
+                break;
+            }
+            let name = word.splitn(2, ':').next().unwrap();
+            result.push(name.to_string());
+        }
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use super::{Config, ServerSelection, DEFAULT_PAYLOAD_SIZE};
+
+    fn parse(data: &str) -> Config {
+        let mut config = Config::new(Vec::new());
+        config.parse_resolv_conf(data.as_bytes()).unwrap();
+        config
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse("nameserver 10.0.0.1\n");
+        let ns: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        assert_eq!(config.nameservers, vec![ns]);
+        assert_eq!(config.timeout, Duration::new(5, 0));
+        assert_eq!(config.attempts, 2);
+        assert_eq!(config.ndots, 1);
+        assert_eq!(config.edns_payload_size, Some(DEFAULT_PAYLOAD_SIZE));
+        assert_eq!(config.selection, ServerSelection::Sequential);
+        assert!(!config.no_aaaa);
+        assert!(!config.inet6);
+    }
+
+    #[test]
+    fn no_nameservers() {
+        let config = parse("search example.com\n");
+        let ns: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        assert_eq!(config.nameservers, vec![ns]);
+        assert_eq!(config.search, vec!["example.com".to_string()]);
+    }
+
+    #[test]
+    fn options() {
+        let config = parse("\
+            nameserver 10.0.0.1\n\
+            options ndots:2 timeout:1 attempts:3\n\
+            options no-aaaa rotate single-request use-vc\n");
+        assert_eq!(config.ndots, 2);
+        assert_eq!(config.timeout, Duration::new(1, 0));
+        assert_eq!(config.attempts, 3);
+        assert!(config.no_aaaa);
+        assert_eq!(config.selection, ServerSelection::Rotate);
+        assert!(config.single_request);
+        assert!(config.use_vc);
+    }
+
+    #[test]
+    fn keeps_settings() {
+        let mut config = Config::new(Vec::new());
+        config.timeout = Duration::new(2, 0);
+        config.no_aaaa = true;
+        config.inet6 = true;
+        config.edns_payload_size = Some(4096);
+        config.selection = ServerSelection::LowestRtt;
+        config.parse_resolv_conf(b"nameserver 10.0.0.1\n").unwrap();
+        assert_eq!(config.timeout, Duration::new(2, 0));
+        assert!(config.no_aaaa);
+        assert!(config.inet6);
+        assert_eq!(config.edns_payload_size, Some(4096));
+        assert_eq!(config.selection, ServerSelection::LowestRtt);
+    }
+
+    #[test]
+    fn edns0_enables_edns() {
+        let mut config = Config::new(Vec::new());
+        config.edns_payload_size = None;
+        config.parse_resolv_conf(b"options edns0\n").unwrap();
+        assert_eq!(config.edns_payload_size, Some(DEFAULT_PAYLOAD_SIZE));
+    }
+
+    #[test]
+    fn commented_options() {
+        let config = parse("\
+            # options no-aaaa\n\
+            options ndots:3\n");
+        assert_eq!(config.ndots, 3);
+        assert!(!config.no_aaaa);
     }
 }
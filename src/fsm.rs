@@ -1,103 +1,213 @@
 use std::i32;
-use std::cmp::{min};
+use std::cmp::{min, max};
 use std::sync::Arc;
+use std::mem::replace;
+use std::borrow::Cow;
+use std::ops::Add;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io::ErrorKind::Interrupted;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rand::{thread_rng, Rng};
 use rotor::void::{unreachable, Void};
-use dns_parser::{Packet, QueryType, QueryClass, RRData, Builder};
-use rotor::{Machine, EventSet, Scope, Response, Time};
+use dns_parser::{Packet, QueryClass, RRData, Builder};
+use dns_parser::ResponseCode;
+use rotor::{Machine, EventSet, PollOpt, Scope, Response, Time};
 
 use {Fsm, Request, Query, Answer, CacheEntry, DnsMachine, Id, QueryError};
-use {TimeEntry, MxRecord, SrvRecord};
+use {QueryOptions};
+use {TimeEntry, MxRecord, SrvRecord, Waiter, HostLookup, SearchLookup};
+use {NsRecord, SoaRecord, RawAnswer};
+use {Config};
+use tcp::{Connection, Progress};
+use wire::{self, HEADER_SIZE, TRUNCATED_FLAG};
+
+/// Maximum size of the UDP response without EDNS (RFC 1035, 4.2.1)
+const PLAIN_PAYLOAD_SIZE: usize = 512;
+/// Seconds the answer from hosts file is valid, as the file may change
+const HOSTS_TTL: u64 = 5;
+/// Maximum length of CNAME chain we follow
+const MAX_ALIASES: usize = 8;
+/// Upper bound (in seconds) for the timeout of a single attempt, unless
+/// configured timeout is larger
+const MAX_ATTEMPT_TIMEOUT: u64 = 30;
 
 impl Request {
+    /// The name that is currently queried, i.e. the last alias followed
+    pub fn wire_name(&self) -> Cow<str> {
+        match self.aliases.last() {
+            Some(name) => Cow::Borrowed(name),
+            None => self.query.name(),
+        }
+    }
+    /// The query that is actually sent to the nameserver
+    pub fn wire_query(&self) -> Query {
+        match self.aliases.last() {
+            Some(name) => self.query.with_name(name.clone()),
+            None => self.query.clone(),
+        }
+    }
+    /// Follows the chain of CNAME records, given as owner and target
+    /// names, starting from the name that was queried last
+    ///
+    /// Returns false if the chain is looped or too long
+    pub fn follow_aliases(&mut self, cnames: &[(String, String)]) -> bool {
+        loop {
+            let target = cnames.iter()
+                .filter(|&&(ref owner, _)| {
+                    same_name(owner, &self.wire_name())
+                })
+                .map(|&(_, ref target)| target.clone())
+                .next();
+            let target = match target {
+                Some(target) => target,
+                None => return true,
+            };
+            let looped = same_name(&target, &self.query.name()) ||
+                self.aliases.iter().any(|x| same_name(x, &target));
+            if looped || self.aliases.len() >= MAX_ALIASES {
+                return false;
+            }
+            self.aliases.push(target);
+        }
+    }
+    /// The key of the request in `DnsMachine::queued`
+    pub fn key(&self) -> (Query, QueryOptions) {
+        (self.query.clone(), self.options)
+    }
     pub fn matches(&self, pack: &Packet) -> bool {
         if pack.questions.len() != 1 {
             return false;
         }
         let ref q = pack.questions[0];
-        match self.query {
-            Query::LookupIpv4(ref host) => {
-                if q.qtype != QueryType::A || q.qclass != QueryClass::IN {
-                    return false;
-                }
-                // TODO(tailhook) optimize the comparison
-                if &format!("{}", q.qname) != host {
-                    return false;
-                }
-            }
-            Query::LookupSrv(ref host) => {
-                if q.qtype != QueryType::SRV || q.qclass != QueryClass::IN {
-                    return false;
-                }
-                // TODO(tailhook) optimize the comparison
-                if &format!("{}", q.qname) != host {
-                    return false;
-                }
-            }
-            Query::LookupMx(ref host) => {
-                if q.qtype != QueryType::MX || q.qclass != QueryClass::IN {
-                    return false;
-                }
-                // TODO(tailhook) optimize the comparison
-                if &format!("{}", q.qname) != host {
-                    return false;
-                }
-            }
-        }
-        return true;
+        let qtype = match self.query.query_type() {
+            Some(qtype) => qtype,
+            // Never sent as is, or not parsed by `dns_parser`
+            None => return false,
+        };
+        q.qtype == qtype && q.qclass == QueryClass::IN &&
+            same_name(&q.qname.to_string(), &self.wire_name())
     }
 }
 
 impl DnsMachine {
     fn refresh_timeouts<C>(&mut self, scope: &mut Scope<C>) -> Option<Time> {
         let now = scope.now();
+        self.check_reload(now);
+        let sweep = self.sweep_cache(now);
         while self.timeouts.peek().map(|x| x.0 < now).unwrap_or(false) {
             let id = self.timeouts.pop().unwrap().1;
-            if let Some(mut req) = self.running.remove(&id) {
-                if req.deadline >= now {
-                    continue;
-                }
-                if req.attempts >= self.config.attempts {
-                    let bad_cache = Arc::new(CacheEntry {
-                        value: Answer::ServerUnavailable,
-                        // TODO(tailhook) should it expire later?
-                        expire: scope.now(),
-                    });
-                    for (arc, notifier) in req.notifiers.into_iter() {
-                        arc.lock().as_mut()
-                            .map(|x| **x = Some(bad_cache.clone()))
-                            .ok();
-                        notifier.wakeup().unwrap();
-                    }
-                } else {
-                    req.attempts += 1;
-                    req.nameserver_index = (req.nameserver_index + 1)
-                                           % self.config.nameservers.len();
-                    req.server = self.config.nameservers[req.nameserver_index];
-                    req.deadline = scope.now() + self.config.timeout;
-
-                    // There are two kind of errors:
-                    // 1. Truncated packet, should never happen because we
-                    //    generate exactly same packet as first time
-                    // 2. Can't send message. Usually not happen second time
-                    //    too, but in case it is, we treat it as a packet
-                    //    loss (i.e. retry after a timeout)
-                    self.send_request(&req.query, req.nameserver_index)
-                        .map(|x| { req.id = x; }).ok(); // TODO(tailhook) log?
-                    // TODO(tailhook) is it okay to put back with same id ?
-                    self.timeouts.push(TimeEntry(req.deadline, req.id));
-                    self.running.insert(req.id, req);
+            let (expired, hedge) = match self.running.get(&id) {
+                Some(req) => (req.deadline < now,
+                              req.hedge_at.map(|x| x < now).unwrap_or(false)),
+                None => continue,
+            };
+            let mut req = self.running.remove(&id).unwrap();
+            if expired {
+                self.retry(req, scope);
+            } else {
+                if hedge {
+                    // No answer for too long, ask one more nameserver
+                    req.hedge_at = None;
+                    self.send_hedge(&mut req);
                 }
+                self.running.insert(id, req);
+            }
+        }
+        // Failed requests might have started the next name of the search
+        // list or the next part of the host lookup, and connections for
+        // them are opened here, so this goes after all other handlers
+        self.connect_pending(scope);
+        let next = match (self.timeouts.peek().map(|x| x.0), self.next_reload)
+        {
+            (Some(timeout), Some(reload)) => min(timeout, reload),
+            (timeout, reload) => timeout.or(reload).unwrap_or(sweep),
+        };
+        Some(min(next, sweep))
+    }
+    /// Repeats request using next nameserver, or reports failure to the
+    /// waiters if there are no attempts left
+    fn retry<C>(&mut self, mut req: Request, scope: &mut Scope<C>) {
+        close_tcp(&mut req, scope);
+        self.server_failed(req.server);
+        for hedge in &req.hedges {
+            self.server_failed(hedge.server);
+        }
+        self.clear_hedges(&mut req);
+        let now = scope.now();
+        let expired = req.expires.map(|x| x <= now).unwrap_or(false);
+        if req.attempts >= self.config.attempts || expired {
+            let value = req.last_error.map(Answer::Error)
+                .unwrap_or(Answer::ServerUnavailable);
+            self.fail(req, value, now);
+            return;
+        }
+        req.attempts += 1;
+        req.nameserver_index = self.select_server(Some(req.nameserver_index));
+        req.server = self.config.nameservers[req.nameserver_index];
+        req.deadline = self.attempt_deadline(&req, now);
+
+        if req.use_tcp {
+            req.id = self.new_id();
+            // Connection errors are treated as a packet loss too
+            self.connect_tcp(&mut req, scope).ok();
+        } else {
+            // There are two kind of errors:
+            // 1. Truncated packet, should never happen because we
+            //    generate exactly same packet as first time
+            // 2. Can't send message. Usually not happen second time
+            //    too, but in case it is, we treat it as a packet
+            //    loss (i.e. retry after a timeout)
+            if self.send_udp(&mut req).is_ok() { // TODO(tailhook) log?
+                self.start_hedging(&mut req, now);
             }
         }
-        return self.timeouts.peek().map(|x| x.0)
+        // TODO(tailhook) is it okay to put back with same id ?
+        self.timeouts.push(TimeEntry(req.deadline, req.id));
+        self.queued.insert(req.key(), req.id);
+        self.running.insert(req.id, req);
+    }
+    /// Deadline of the current attempt of the request
+    ///
+    /// Timeout is doubled on each round through the nameserver list (as
+    /// in glibc), but attempt never outlives the deadline of the query
+    fn attempt_deadline(&self, req: &Request, now: Time) -> Time {
+        let base = req.options.timeout.unwrap_or(self.config.timeout);
+        attempt_deadline(now, base, req.attempts,
+            self.config.nameservers.len(), req.expires)
+    }
+    /// Reports an error to everyone waiting for the request
+    pub fn fail(&mut self, mut req: Request, value: Answer, now: Time) {
+        self.clear_hedges(&mut req);
+        let unreachable = match value {
+            Answer::ServerUnavailable => true,
+            Answer::Error(ResponseCode::ServerFailure) => true,
+            Answer::Error(ResponseCode::Refused) => true,
+            _ => false,
+        };
+        let stale = if unreachable && req.options.reads_cache() {
+            self.stale_entry(&req.query, now)
+        } else {
+            None
+        };
+        let bad_cache = stale.unwrap_or_else(|| Arc::new(CacheEntry {
+            value: value,
+            // TODO(tailhook) should it expire later?
+            expire: now,
+            canonical_name: req.aliases.last().cloned(),
+            name: req.query.name().to_string(),
+            stale: false,
+        }));
+        self.queued.remove(&req.key());
+        self.finish(req.notifiers, bad_cache, now);
     }
     fn recv_messages<C>(&mut self, scope: &mut Scope<C>) {
+        // One byte more than expected, to find out if datagram is cut
+        let size = self.config.edns_payload_size
+            .map(|x| max(x as usize, PLAIN_PAYLOAD_SIZE))
+            .unwrap_or(PLAIN_PAYLOAD_SIZE) + 1;
+        let mut buf = vec![0u8; size];
         loop {
-            let mut buf = [0u8; 4096];
             let (bytes, addr) = match self.sock.recv_from(&mut buf) {
                 Ok(Some((bytes, addr))) => (bytes, addr),
                 Ok(None) => break,
@@ -109,126 +219,955 @@ impl DnsMachine {
                     break;
                 }
             };
-            assert!(bytes < buf.len()); // TODO(tailhook) truncated
-            let pack = match Packet::parse(&buf[..bytes]) {
-                Ok(pack) => pack,
+            if bytes < HEADER_SIZE {
+                // Just a bad packet. Should we log it?
+                continue;
+            }
+            // Datagram that doesn't fit the buffer is cut by the kernel,
+            // so it's handled the same way as a truncated response
+            if bytes >= buf.len() || buf[2] & TRUNCATED_FLAG != 0 {
+                let id = ((buf[0] as u16) << 8) | buf[1] as u16;
+                self.truncated(id, addr, scope);
+                continue;
+            }
+            self.handle_response(&buf[..bytes], addr, false, scope);
+        }
+    }
+    /// Repeats the request over TCP, as the UDP response was truncated
+    fn truncated<C>(&mut self, id: Id, addr: SocketAddr,
+        scope: &mut Scope<C>)
+    {
+        let primary = self.hedged.get(&id).cloned().unwrap_or(id);
+        let mut req = match self.running.remove(&primary) {
+            Some(request) => request,
+            None => {
+                // Unsolicited reply. Should we log it?
+                return;
+            }
+        };
+        if req.server_for(id) != Some(addr) || req.use_tcp {
+            // Probably someone tries to spoof us. Log it?
+            self.running.insert(req.id, req);
+            return;
+        }
+        req.use_copy(id);
+        self.clear_hedges(&mut req);
+        req.use_tcp = true;
+        req.deadline = self.attempt_deadline(&req, scope.now());
+        if self.connect_tcp(&mut req, scope).is_err() {
+            self.retry(req, scope);
+            return;
+        }
+        self.timeouts.push(TimeEntry(req.deadline, req.id));
+        self.running.insert(req.id, req);
+    }
+    /// Repeats the request without OPT record, because server doesn't
+    /// support EDNS (RFC 6891, section 7)
+    fn disable_edns<C>(&mut self, mut req: Request, scope: &mut Scope<C>) {
+        req.edns = false;
+        self.resend(req, scope);
+    }
+    /// Sends the request to the same nameserver again with a new id
+    pub fn resend<C>(&mut self, mut req: Request, scope: &mut Scope<C>) {
+        let now = scope.now();
+        self.clear_hedges(&mut req);
+        req.deadline = self.attempt_deadline(&req, now);
+        let sent = if req.use_tcp {
+            req.id = self.new_id();
+            self.connect_tcp(&mut req, scope)
+        } else {
+            self.send_udp(&mut req)
+                .map(|()| self.start_hedging(&mut req, now))
+        };
+        if sent.is_err() {
+            self.retry(req, scope);
+            return;
+        }
+        self.timeouts.push(TimeEntry(req.deadline, req.id));
+        self.queued.insert(req.key(), req.id);
+        self.running.insert(req.id, req);
+    }
+    /// Opens connections for requests started by `start_query`
+    ///
+    /// Failing to connect may start new requests too, so this repeats
+    /// until there are no pending requests left
+    fn connect_pending<C>(&mut self, scope: &mut Scope<C>) {
+        while !self.pending_tcp.is_empty() {
+            for id in replace(&mut self.pending_tcp, Vec::new()) {
+                let mut req = match self.running.remove(&id) {
+                    Some(req) => req,
+                    None => continue,
+                };
+                match self.connect_tcp(&mut req, scope) {
+                    Ok(()) => {
+                        self.running.insert(req.id, req);
+                    }
+                    Err(_) => self.retry(req, scope),
+                }
+            }
+        }
+    }
+    fn poll_tcp<C>(&mut self, scope: &mut Scope<C>) {
+        let mut complete = Vec::new();
+        for (&id, req) in self.running.iter_mut() {
+            if let Some(ref mut conn) = req.tcp {
+                match conn.poll() {
+                    Progress::Wait => {}
+                    Progress::Done(data) => complete.push((id, Some(data))),
+                    Progress::Failed => complete.push((id, None)),
+                }
+            }
+        }
+        for (id, result) in complete {
+            let mut req = self.running.remove(&id).unwrap();
+            close_tcp(&mut req, scope);
+            match result {
+                Some(data) => {
+                    let server = req.server;
+                    self.running.insert(id, req);
+                    self.handle_response(&data, server, true, scope);
+                }
+                None => self.retry(req, scope),
+            }
+        }
+    }
+    fn handle_response<C>(&mut self, data: &[u8], addr: SocketAddr,
+        tcp: bool, scope: &mut Scope<C>)
+    {
+        let data = match wire::strip_opt(data) {
+            Some(data) => data,
+            None => {
+                // Just a bad packet. Should we log it?
+                return;
+            }
+        };
+        let id = wire::read_u16(&data, 0);
+        let primary = self.hedged.get(&id).cloned().unwrap_or(id);
+        let mut req = match self.running.remove(&primary) {
+            Some(request) => request,
+            None => {
+                // Unsolicited reply. Should we log it?
+                return;
+            }
+        };
+        // Some queries have types that `dns_parser` can't parse
+        let pack = match req.query.raw_type() {
+            Some(_) => None,
+            None => match Packet::parse(&data) {
+                Ok(pack) => Some(pack),
                 Err(_) => {
                     // Just a bad packet. Should we log it?
-                    continue;
+                    self.running.insert(req.id, req);
+                    return;
                 }
-            };
-            let req = match self.running.remove(&pack.header.id) {
-                Some(request) => request,
-                None => {
-                    // Unsolicited reply. Should we log it?
-                    continue;
+            },
+        };
+        let (valid, code) = match pack {
+            Some(ref pack) => (req.matches(pack), pack.header.response_code),
+            None => (req.matches_raw(&data), wire::response_code(&data)),
+        };
+        if req.server_for(id) != Some(addr) || req.use_tcp != tcp || !valid {
+            // Probably someone tries to spoof us. Log it?
+            self.running.insert(req.id, req);
+            return;
+        }
+        match code {
+            ResponseCode::ServerFailure | ResponseCode::Refused
+            if req.hedges.len() > 0 => {
+                // Wait for the other copies of the request
+                req.last_error = Some(code);
+                self.server_failed(addr);
+                self.drop_copy(req, id);
+                return;
+            }
+            _ => {}
+        }
+        req.use_copy(id);
+        self.clear_hedges(&mut req);
+        if let ResponseCode::FormatError = code {
+            if req.edns {
+                self.disable_edns(req, scope);
+                return;
+            }
+        }
+        let name_error = match code {
+            ResponseCode::NoError => false,
+            ResponseCode::NameError => true,
+            ResponseCode::ServerFailure | ResponseCode::Refused => {
+                // Other nameservers might be able to answer
+                req.last_error = Some(code);
+                self.retry(req, scope);
+                return;
+            }
+            code => {
+                self.fail(req, Answer::Error(code), scope.now());
+                return;
+            }
+        };
+        self.server_responded(req.server, req.sent);
+        let pack = match pack {
+            Some(pack) => pack,
+            None => {
+                self.handle_raw(req, &data, name_error, scope);
+                return;
+            }
+        };
+        let aliases = req.aliases.len();
+        let cnames = pack.answers.iter().filter_map(|rr| match rr.data {
+            RRData::CNAME(ref target) => {
+                Some((rr.name.to_string(), target.to_string()))
+            }
+            _ => None,
+        }).collect::<Vec<_>>();
+        let req = match self.follow_cnames(req, &cnames, scope.now()) {
+            Some(req) => req,
+            None => return,
+        };
+        let negative_ttl = negative_ttl(pack.nameservers.iter()
+            .filter_map(|rr| match rr.data {
+                RRData::SOA(ref soa) => Some((rr.ttl, soa.minimum_ttl)),
+                _ => None,
+            }));
+        let ttl = pack.answers.iter().map(|rr| rr.ttl)
+            .fold(i32::MAX as u32, min);
+        let answers = pack.answers.into_iter()
+            .filter(|rr| same_name(&rr.name.to_string(), &req.wire_name()))
+            .collect::<Vec<_>>();
+        let result = match req.query {
+            Query::LookupIpv4(_) => {
+                let mut ips = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::A(ip) => {
+                            ips.push(ip);
+                        }
+                        _ => {
+                            // Bad value. Log it?
+                        }
+                    }
                 }
-            };
-            if req.server != addr || !req.matches(&pack) {
-                // Probably someone tries to spoof us. Log it?
-                self.running.insert(req.id, req);
-                continue;
+                Answer::Ipv4(ips)
             }
-            let mut ttl = i32::MAX as u32;
-            let result = match req.query {
-                Query::LookupIpv4(_) => {
-                    let mut ips = Vec::with_capacity(pack.answers.len());
-                    for ans in pack.answers {
-                        ttl = min(ttl, ans.ttl);
-                        match ans.data {
-                            RRData::A(ip) => {
-                                ips.push(ip);
-                            }
-                            _ => {
-                                // Bad value. Log it?
-                            }
+            Query::LookupIpv6(_) => {
+                let mut ips = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::AAAA(ip) => {
+                            ips.push(ip);
+                        }
+                        _ => {
+                            // Bad value. Log it?
                         }
                     }
-                    Answer::Ipv4(ips)
                 }
-                Query::LookupMx(_) => {
-                    let mut rows = Vec::with_capacity(pack.answers.len());
-                    for ans in pack.answers {
-                        ttl = min(ttl, ans.ttl);
-                        match ans.data {
-                            RRData::MX { preference, exchange } => {
-                                rows.push(MxRecord {
-                                    preference: preference,
-                                    exchange: exchange.to_string(),
-                                });
-                            }
-                            _ => {
-                                // Bad value. Log it?
-                            }
+                Answer::Ipv6(ips)
+            }
+            Query::LookupHost(_) => unreachable!(),
+            Query::LookupMx(_) => {
+                let mut rows = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::MX { preference, exchange } => {
+                            rows.push(MxRecord {
+                                preference: preference,
+                                exchange: exchange.to_string(),
+                            });
+                        }
+                        _ => {
+                            // Bad value. Log it?
                         }
                     }
-                    Answer::Mx(rows)
                 }
-                Query::LookupSrv(_) => {
-                    let mut rows = Vec::with_capacity(pack.answers.len());
-                    for ans in pack.answers {
-                        ttl = min(ttl, ans.ttl);
-                        match ans.data {
-                            RRData::SRV { priority, weight, port, target } => {
-                                rows.push(SrvRecord {
-                                    priority: priority,
-                                    weight: weight,
-                                    port: port,
-                                    target: target.to_string(),
-                                });
-                            }
-                            _ => {
-                                // Bad value. Log it?
-                            }
+                Answer::Mx(rows)
+            }
+            Query::LookupSrv(_) => {
+                let mut rows = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::SRV { priority, weight, port, target } => {
+                            rows.push(SrvRecord {
+                                priority: priority,
+                                weight: weight,
+                                port: port,
+                                target: target.to_string(),
+                            });
+                        }
+                        _ => {
+                            // Bad value. Log it?
                         }
                     }
-                    Answer::Srv(rows)
                 }
-            };
-            let entry = CacheEntry {
-                value: result,
-                expire: scope.now() + Duration::new(ttl.into(), 0),
-            };
-            let cache = Arc::new(entry);
-            for (result, notifier) in req.notifiers {
-                result.lock().as_mut()
-                    .map(|x| **x = Some(cache.clone())).ok();
+                Answer::Srv(rows)
+            }
+            Query::LookupTxt(_) => {
+                // Parsed here, because `dns_parser` joins the strings
+                let records = wire::sections(&data)
+                    .map(|x| x.answers).unwrap_or(Vec::new());
+                let mut rows = Vec::with_capacity(answers.len());
+                for rec in records {
+                    if rec.rtype != wire::TXT_TYPE ||
+                        !same_name(&rec.name, &req.wire_name())
+                    {
+                        continue;
+                    }
+                    match wire::character_strings(&rec.data) {
+                        Some(strings) => rows.push(strings),
+                        None => {
+                            // Bad value. Log it?
+                        }
+                    }
+                }
+                Answer::Txt(rows)
+            }
+            Query::LookupPtr(_) => {
+                let mut names = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::PTR(name) => {
+                            names.push(name.to_string());
+                        }
+                        _ => {
+                            // Bad value. Log it?
+                        }
+                    }
+                }
+                Answer::Ptr(names)
+            }
+            Query::LookupNs(_) => {
+                let mut rows = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::NS(name) => {
+                            rows.push(NsRecord {
+                                nameserver: name.to_string(),
+                            });
+                        }
+                        _ => {
+                            // Bad value. Log it?
+                        }
+                    }
+                }
+                Answer::Ns(rows)
+            }
+            Query::LookupSoa(_) => {
+                let mut rows = Vec::with_capacity(answers.len());
+                for ans in answers {
+                    match ans.data {
+                        RRData::SOA(soa) => {
+                            rows.push(SoaRecord {
+                                mname: soa.primary_ns.to_string(),
+                                rname: soa.mailbox.to_string(),
+                                serial: soa.serial,
+                                refresh: soa.refresh,
+                                retry: soa.retry,
+                                expire: soa.expire,
+                                minimum: soa.minimum_ttl,
+                            });
+                        }
+                        _ => {
+                            // Bad value. Log it?
+                        }
+                    }
+                }
+                Answer::Soa(rows)
+            }
+            Query::ReverseLookup(_) => unreachable!(),
+            Query::Raw { .. } | Query::LookupCaa(_) | Query::LookupTlsa(_)
+            | Query::LookupSshfp(_) => unreachable!(),
+        };
+        self.answer_response(req, result, name_error, aliases,
+                             ttl, negative_ttl, scope);
+    }
+    /// Follows the aliases that nameserver included in the response,
+    /// given as owner and target names
+    ///
+    /// Fails the request if the chain is looped or too long.
+    pub fn follow_cnames(&mut self, mut req: Request,
+        cnames: &[(String, String)], now: Time)
+        -> Option<Request>
+    {
+        if req.follow_aliases(cnames) {
+            Some(req)
+        } else {
+            self.fail(req, Answer::CnameLoop, now);
+            None
+        }
+    }
+    /// Finishes the request with the answer for the name queried last
+    ///
+    /// If the answer is empty, because nameserver returned only the
+    /// aliases (the chain was `aliases` long before the response), their
+    /// target is queried. Otherwise empty answer is reported as NXDOMAIN
+    /// or NODATA, which is cached for `negative_ttl`.
+    pub fn answer_response<C>(&mut self, mut req: Request, result: Answer,
+        name_error: bool, aliases: usize, ttl: u32, negative_ttl: u32,
+        scope: &mut Scope<C>)
+    {
+        if result.is_empty() && !name_error && req.aliases.len() > aliases {
+            req.attempts = 1;
+            self.resend(req, scope);
+            return;
+        }
+        let (result, ttl) = if name_error {
+            (Answer::NxDomain, negative_ttl)
+        } else if result.is_empty() {
+            (Answer::NoData, negative_ttl)
+        } else {
+            (result, ttl)
+        };
+        self.answer(req, result, ttl, scope.now());
+    }
+    /// Stores the answer to the cache and delivers it to the waiters
+    pub fn answer(&mut self, req: Request, result: Answer, ttl: u32,
+        now: Time)
+    {
+        let ttl = min(max(Duration::new(ttl.into(), 0), self.config.min_ttl),
+                      self.config.max_ttl);
+        let entry = CacheEntry {
+            value: result,
+            expire: now + ttl,
+            canonical_name: req.aliases.last().cloned(),
+            name: req.query.name().to_string(),
+            stale: false,
+        };
+        let cache = Arc::new(entry);
+        self.queued.remove(&req.key());
+        let Request { query, options, notifiers, .. } = req;
+        // Zero TTL means the answer must not be cached (RFC 1035, 3.2.1)
+        if cache.expire > now && options.writes_cache() {
+            self.cache.insert(query, cache.clone());
+        }
+        self.finish(notifiers, cache, now);
+    }
+    fn cached(&mut self, query: &Query, now: Time) -> Option<Arc<CacheEntry>>
+    {
+        self.cache.get(query, now)
+    }
+    /// Starts resolving a query, trying names from the search list
+    /// for relative domain names
+    pub fn start_search(&mut self, query: Query, options: QueryOptions,
+        waiter: Waiter, now: Time)
+        -> Result<(), QueryError>
+    {
+        if let Query::ReverseLookup(ip) = query {
+            // Arpa name is absolute, so search list is not used
+            let query = Query::LookupPtr(Query::reverse_name(ip));
+            return self.start_query(query, options, waiter, now);
+        }
+        // Hosts file is looked up by the name as is, like glibc does
+        if let Some(entry) = self.from_hosts(&query, now) {
+            self.deliver(waiter, entry, now);
+            return Ok(());
+        }
+        let mut candidates = search_list(&self.config, &query.name());
+        if candidates.len() == 1 {
+            let query = query.with_name(candidates.pop().unwrap());
+            return self.start_query(query, options, waiter, now);
+        }
+        if options.reads_cache() {
+            if let Some(cache) = self.cached(&query, now) {
+                self.deliver(waiter, cache, now);
+                return Ok(());
+            }
+        }
+        let key = (query, options);
+        if let Some(search) = self.searches.get_mut(&key) {
+            search.notifiers.push(waiter);
+            return Ok(());
+        }
+        candidates.reverse();
+        let first = candidates.pop().unwrap();
+        self.searches.insert(key.clone(), SearchLookup {
+            candidates: candidates,
+            got_nodata: false,
+            notifiers: vec![waiter],
+        });
+        let (query, options) = key;
+        let res = self.start_query(query.with_name(first), options,
+                                   Waiter::Search(query.clone(), options),
+                                   now);
+        if res.is_err() {
+            self.searches.remove(&(query, options));
+        }
+        res
+    }
+    /// Answers address queries for the names listed in the hosts file
+    fn from_hosts(&self, query: &Query, now: Time) -> Option<Arc<CacheEntry>>
+    {
+        let name = query.name();
+        let name = name.trim_right_matches('.');
+        let value = match *query {
+            Query::LookupIpv4(_) => match self.hosts.ipv4(name) {
+                Some(ips) => Answer::Ipv4(ips.clone()),
+                None => return None,
+            },
+            Query::LookupIpv6(_) => match self.hosts.ipv6(name) {
+                Some(ips) => Answer::Ipv6(ips.clone()),
+                None => return None,
+            },
+            Query::LookupHost(_) => {
+                match (self.hosts.ipv6(name), self.hosts.ipv4(name)) {
+                    (None, None) => return None,
+                    (v6, v4) => Answer::Addresses(self.order_addresses(
+                        v6.map(|x| &x[..]).unwrap_or(&[]),
+                        v4.map(|x| &x[..]).unwrap_or(&[]))),
+                }
+            }
+            _ => return None,
+        };
+        Some(Arc::new(CacheEntry {
+            value: value,
+            expire: now + Duration::new(HOSTS_TTL, 0),
+            canonical_name: None,
+            name: name.to_string(),
+            stale: false,
+        }))
+    }
+    /// Starts resolving a query, or answers it right away from the cache
+    pub fn start_query(&mut self, query: Query, options: QueryOptions,
+        waiter: Waiter, now: Time)
+        -> Result<(), QueryError>
+    {
+        if let Some(entry) = self.from_hosts(&query, now) {
+            self.deliver(waiter, entry, now);
+            return Ok(());
+        }
+        if options.reads_cache() {
+            if let Some(cache) = self.cached(&query, now) {
+                self.deliver(waiter, cache, now);
+                return Ok(());
+            }
+        }
+        let key = (query, options);
+        if let Some(&id) = self.queued.get(&key) {
+            // Same query is already in flight, just wait for it too
+            self.running.get_mut(&id).unwrap().notifiers.push(waiter);
+            return Ok(());
+        }
+        let (query, options) = key;
+        if let Query::LookupHost(name) = query {
+            return self.start_host_lookup(name, options, waiter, now);
+        }
+        if let Query::LookupIpv6(ref name) = query {
+            if self.config.no_aaaa {
+                let entry = Arc::new(CacheEntry {
+                    value: Answer::NoData,
+                    expire: now,
+                    canonical_name: None,
+                    name: name.clone(),
+                    stale: false,
+                });
+                self.deliver(waiter, entry, now);
+                return Ok(());
+            }
+        }
+        let server = self.select_server(None);
+        let mut req = Request {
+            id: 0,
+            query: query,
+            options: options,
+            nameserver_index: server,
+            attempts: 1,
+            server: self.config.nameservers[server],
+            sent: Instant::now(),
+            deadline: now,
+            expires: options.query_timeout.or(self.config.query_timeout)
+                .map(|x| now + x),
+            notifiers: vec![waiter],
+            last_error: None,
+            aliases: Vec::new(),
+            edns: self.config.edns_payload_size.is_some(),
+            use_tcp: self.config.use_vc,
+            tcp: None,
+            hedges: Vec::new(),
+            hedge_at: None,
+        };
+        req.deadline = self.attempt_deadline(&req, now);
+        if req.use_tcp {
+            // We can't register socket in the scope of another state
+            // machine, so connection is opened on the next wakeup
+            req.id = self.new_id();
+            self.pending_tcp.push(req.id);
+        } else {
+            try!(self.send_udp(&mut req));
+            self.start_hedging(&mut req, now);
+        }
+        self.timeouts.push(TimeEntry(req.deadline, req.id));
+        self.queued.insert(req.key(), req.id);
+        self.running.insert(req.id, req);
+        Ok(())
+    }
+    fn start_host_lookup(&mut self, name: String, options: QueryOptions,
+        waiter: Waiter, now: Time)
+        -> Result<(), QueryError>
+    {
+        let key = (name, options);
+        if let Some(lookup) = self.host_lookups.get_mut(&key) {
+            lookup.notifiers.push(waiter);
+            return Ok(());
+        }
+        let no_aaaa = self.config.no_aaaa;
+        self.host_lookups.insert(key.clone(), HostLookup {
+            ipv4: None,
+            ipv6: None,
+            next: None,
+            no_aaaa: no_aaaa,
+            notifiers: vec![waiter],
+        });
+        let ipv4 = Query::LookupIpv4(key.0.clone());
+        if no_aaaa {
+            let res = self.start_host_part(ipv4, options, now);
+            if res.is_err() {
+                self.host_lookups.remove(&key);
+            }
+            return res;
+        }
+        let ipv6 = Query::LookupIpv6(key.0.clone());
+        let (first, second) = if self.config.inet6 {
+            (ipv6, ipv4)
+        } else {
+            (ipv4, ipv6)
+        };
+        // Both parts may be answered from the cache immediately, so the
+        // lookup might be already finished when these calls return
+        let res = if self.config.single_request {
+            self.host_lookups.get_mut(&key).unwrap().next = Some(second);
+            self.start_host_part(first, options, now)
+        } else {
+            self.start_host_part(first, options, now)
+                .and_then(|()| self.start_host_part(second, options, now))
+        };
+        if res.is_err() {
+            // The part that is already in flight will be ignored
+            self.host_lookups.remove(&key);
+        }
+        res
+    }
+    fn start_host_part(&mut self, query: Query, options: QueryOptions,
+        now: Time)
+        -> Result<(), QueryError>
+    {
+        let waiter = match query {
+            Query::LookupIpv6(ref name) => {
+                Waiter::HostIpv6(name.clone(), options)
+            }
+            ref query => Waiter::HostIpv4(query.name().to_string(), options),
+        };
+        self.start_query(query, options, waiter, now)
+    }
+    /// Starts the second part of `Query::LookupHost` in single-request mode
+    fn continue_host_lookup(&mut self, key: &(String, QueryOptions),
+        now: Time)
+    {
+        let next = self.host_lookups.get_mut(key)
+            .and_then(|lookup| lookup.next.take());
+        if let Some(query) = next {
+            if self.start_host_part(query, key.1, now).is_err() {
+                let lookup = self.host_lookups.remove(key).unwrap();
+                let bad_cache = Arc::new(CacheEntry {
+                    value: Answer::ServerUnavailable,
+                    expire: now,
+                    canonical_name: None,
+                    name: key.0.clone(),
+                    stale: false,
+                });
+                self.finish(lookup.notifiers, bad_cache, now);
+            }
+        }
+    }
+    /// Notifies everyone waiting for the request
+    fn finish(&mut self, notifiers: Vec<Waiter>, entry: Arc<CacheEntry>,
+        now: Time)
+    {
+        for waiter in notifiers {
+            self.deliver(waiter, entry.clone(), now);
+        }
+    }
+    fn deliver(&mut self, waiter: Waiter, entry: Arc<CacheEntry>, now: Time)
+    {
+        match waiter {
+            Waiter::Machine(slot, notifier) => {
+                slot.lock().as_mut().map(|x| **x = Some(entry)).ok();
                 notifier.wakeup().unwrap();
             }
-            self.cache.insert(req.query, cache);
+            Waiter::HostIpv4(name, options) => {
+                let key = (name, options);
+                self.host_lookups.get_mut(&key)
+                    .map(|lookup| lookup.ipv4 = Some(entry));
+                self.continue_host_lookup(&key, now);
+                self.check_host_lookup(key, now);
+            }
+            Waiter::HostIpv6(name, options) => {
+                let key = (name, options);
+                self.host_lookups.get_mut(&key)
+                    .map(|lookup| lookup.ipv6 = Some(entry));
+                self.continue_host_lookup(&key, now);
+                self.check_host_lookup(key, now);
+            }
+            Waiter::Search(query, options) => {
+                self.continue_search((query, options), entry, now);
+            }
+        }
+    }
+    /// Tries next name from the search list if the previous one is not found
+    fn continue_search(&mut self, key: (Query, QueryOptions),
+        entry: Arc<CacheEntry>, now: Time)
+    {
+        let next = match self.searches.get_mut(&key) {
+            Some(search) => match entry.value {
+                Answer::NxDomain => search.candidates.pop(),
+                Answer::Raw(RawAnswer {
+                    response_code: ResponseCode::NameError, ..
+                }) => search.candidates.pop(),
+                Answer::NoData => {
+                    search.got_nodata = true;
+                    search.candidates.pop()
+                }
+                Answer::Raw(ref raw) if raw.answers.is_empty() => {
+                    search.got_nodata = true;
+                    search.candidates.pop()
+                }
+                _ => None,
+            },
+            None => return,
+        };
+        if let Some(name) = next {
+            let (ref query, options) = key;
+            let res = self.start_query(query.with_name(name), options,
+                                       Waiter::Search(query.clone(), options),
+                                       now);
+            if res.is_ok() {
+                return;
+            }
+            // Can't send query, so report the last answer we have got
         }
+        let search = self.searches.remove(&key).unwrap();
+        let nxdomain = match entry.value {
+            Answer::NxDomain => true,
+            _ => false,
+        };
+        // Like glibc, report that name exists if any of the names does
+        let entry = if nxdomain && search.got_nodata {
+            Arc::new(CacheEntry {
+                value: Answer::NoData,
+                expire: entry.expire,
+                canonical_name: None,
+                name: entry.name.clone(),
+                stale: entry.stale,
+            })
+        } else {
+            entry
+        };
+        let (query, options) = key;
+        if entry.expire > now && options.writes_cache() {
+            self.cache.insert(query, entry.clone());
+        }
+        self.finish(search.notifiers, entry, now);
     }
-    pub fn send_request(&mut self, query: &Query, idx: usize)
-        -> Result<Id, QueryError>
+    fn check_host_lookup(&mut self, key: (String, QueryOptions),
+        now: Time)
     {
+        let done = self.host_lookups.get(&key)
+            .map(|x| x.ipv4.is_some() && (x.ipv6.is_some() || x.no_aaaa))
+            .unwrap_or(false);
+        if !done {
+            return;
+        }
+        let lookup = self.host_lookups.remove(&key).unwrap();
+        let (name, options) = key;
+        let ipv4 = lookup.ipv4.unwrap();
+        // Without AAAA request the answer is as good as the IPv4 one
+        let ipv6 = lookup.ipv6.unwrap_or_else(|| Arc::new(CacheEntry {
+            value: Answer::NoData,
+            expire: ipv4.expire,
+            canonical_name: None,
+            name: name.clone(),
+            stale: ipv4.stale,
+        }));
+        let value = match (&ipv4.value, &ipv6.value) {
+            (&Answer::Ipv4(ref v4), &Answer::Ipv6(ref v6)) => {
+                Answer::Addresses(self.order_addresses(v6, v4))
+            }
+            (&Answer::Ipv4(ref v4), _) => {
+                Answer::Addresses(self.order_addresses(&[], v4))
+            }
+            (_, &Answer::Ipv6(ref v6)) => {
+                Answer::Addresses(self.order_addresses(v6, &[]))
+            }
+            (&Answer::NxDomain, &Answer::NxDomain) => Answer::NxDomain,
+            (&Answer::NxDomain, &Answer::NoData)
+            | (&Answer::NoData, &Answer::NxDomain)
+            | (&Answer::NoData, &Answer::NoData) => Answer::NoData,
+            (&Answer::Error(code), _) | (_, &Answer::Error(code))
+            => Answer::Error(code),
+            _ => Answer::ServerUnavailable,
+        };
+        let cache = Arc::new(CacheEntry {
+            value: value,
+            expire: min(ipv4.expire, ipv6.expire),
+            canonical_name: ipv6.canonical_name.clone()
+                .or(ipv4.canonical_name.clone()),
+            name: name.clone(),
+            stale: ipv4.stale || ipv6.stale,
+        });
+        if cache.expire > now && options.writes_cache() {
+            self.cache.insert(Query::LookupHost(name), cache.clone());
+        }
+        self.finish(lookup.notifiers, cache, now);
+    }
+    /// Orders addresses for `Query::LookupHost`
+    ///
+    /// With `inet6` option IPv4 addresses are returned as IPv4-mapped IPv6
+    /// ones, like glibc does.
+    fn order_addresses(&self, v6: &[Ipv6Addr], v4: &[Ipv4Addr])
+        -> Vec<IpAddr>
+    {
+        let addresses = interleave(v6, v4);
+        if !self.config.inet6 {
+            return addresses;
+        }
+        addresses.into_iter().map(|ip| match ip {
+            IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+            ip => ip,
+        }).collect()
+    }
+    pub fn new_id(&self) -> Id {
         // TODO(tailhook) limit number of retries somehow
         // Note: we don't use counter here, because this allows us to be
         // a little more resistant to DNS spoofing
         let mut id = thread_rng().gen();
-        while self.running.contains_key(&id) {
+        while self.running.contains_key(&id) || self.hedged.contains_key(&id)
+        {
             id = thread_rng().gen();
         }
-        let mut builder = Builder::new_query(id, true);
-        match query {
-            &Query::LookupIpv4(ref q) => {
-                builder.add_question(q, QueryType::A, QueryClass::IN);
-            }
-            &Query::LookupMx(ref q) => {
-                builder.add_question(q, QueryType::MX, QueryClass::IN);
-            }
-            &Query::LookupSrv(ref q) => {
-                builder.add_question(q, QueryType::SRV, QueryClass::IN);
-            }
+        return id;
+    }
+    pub fn build_request(&self, req: &Request, id: Id)
+        -> Result<Vec<u8>, QueryError>
+    {
+        let query = req.wire_query();
+        let mut pack = if let Some((qtype, qclass)) = query.raw_type() {
+            try!(wire::build_query(id, req.options.recursion,
+                                   &query.name(), qtype, qclass)
+                .ok_or(QueryError::TruncatedPacket))
+        } else {
+            let qtype = match query.query_type() {
+                Some(qtype) => qtype,
+                // `LookupHost` and `ReverseLookup` are never sent as is
+                None => unreachable!(),
+            };
+            let mut builder = Builder::new_query(id, req.options.recursion);
+            builder.add_question(&query.name(), qtype, QueryClass::IN);
+            try!(builder.build()
+                .map_err(|_| QueryError::TruncatedPacket))
+        };
+        match self.config.edns_payload_size {
+            Some(size) if req.edns => wire::add_opt(&mut pack, size),
+            _ => {}
         }
-        let pack = try!(builder.build()
-            .map_err(|_| QueryError::TruncatedPacket));
+        Ok(pack)
+    }
+    /// Sends the request over UDP with a new id
+    fn send_udp(&mut self, req: &mut Request) -> Result<(), QueryError> {
+        let id = self.new_id();
+        let pack = try!(self.build_request(req, id));
+        try!(self.sock.send_to(&pack, &req.server));
+        req.id = id;
+        req.sent = Instant::now();
+        Ok(())
+    }
+    fn connect_tcp<C>(&mut self, req: &mut Request, scope: &mut Scope<C>)
+        -> Result<(), QueryError>
+    {
+        let pack = try!(self.build_request(req, req.id));
+        let conn = try!(Connection::connect(&req.server, &pack));
+        // Edge-triggered, because `Connection::poll` drains both ways
+        try!(scope.register(conn.socket(),
+            EventSet::readable() | EventSet::writable(), PollOpt::edge()));
+        req.tcp = Some(conn);
+        req.sent = Instant::now();
+        Ok(())
+    }
+}
 
-        // TODO(tailhook) better server selection algo
-        let server = self.config.nameservers[idx];
+fn close_tcp<C>(req: &mut Request, scope: &mut Scope<C>) {
+    if let Some(conn) = req.tcp.take() {
+        scope.deregister(conn.socket()).ok();
+    }
+}
 
-        try!(self.sock.send_to(&pack, &server));
-        Ok(id)
+/// Returns names to try for the domain name in order (like glibc does)
+///
+/// Names that have at least `ndots` dots are tried as is first, others
+/// are tried with each of the search domains appended first. Absolute names
+/// (ending with a dot) are never looked up in the search list.
+fn search_list(config: &Config, name: &str) -> Vec<String> {
+    if name.ends_with('.') {
+        return vec![name[..name.len()-1].to_string()];
     }
+    let mut result = Vec::with_capacity(config.search.len() + 1);
+    let dots = name.chars().filter(|&c| c == '.').count();
+    let as_is_first = dots >= config.ndots as usize;
+    if as_is_first {
+        result.push(name.to_string());
+    }
+    for domain in &config.search {
+        result.push(format!("{}.{}", name, domain.trim_right_matches('.')));
+    }
+    if !as_is_first {
+        result.push(name.to_string());
+    }
+    return result;
+}
+
+/// Deadline of the attempt number `attempts` (starting from one) when
+/// there are `servers` nameservers and the first round timeout is `base`
+fn attempt_deadline<T>(now: T, base: Duration, attempts: u32,
+    servers: usize, expires: Option<T>)
+    -> T
+    where T: Add<Duration, Output=T> + Ord + Copy
+{
+    let round = (attempts.saturating_sub(1) as usize / servers) as u32;
+    let limit = max(base, Duration::new(MAX_ATTEMPT_TIMEOUT, 0));
+    let timeout = min(base * (1 << min(round, 16)), limit);
+    match expires {
+        Some(expires) => min(now + timeout, expires),
+        None => now + timeout,
+    }
+}
+
+/// Domain names are compared case-insensitively (RFC 4343)
+fn same_name(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Returns TTL for caching NXDOMAIN and NODATA responses (RFC 2308)
+///
+/// SOA records of the authority section are given as TTL and the minimum
+/// field. Negative answers without SOA record are not cached, unless
+/// `Config::min_ttl` is set
+pub fn negative_ttl<I>(soa: I) -> u32
+    where I: Iterator<Item=(u32, u32)>
+{
+    soa.map(|(ttl, minimum)| min(ttl, minimum)).next().unwrap_or(0)
+}
+
+/// Interleaves address families starting with IPv6 (RFC 8305, section 4)
+fn interleave(v6: &[Ipv6Addr], v4: &[Ipv4Addr]) -> Vec<IpAddr>
+{
+    let mut result = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.iter();
+    let mut v4 = v4.iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => {
+                a.map(|ip| result.push(IpAddr::V6(*ip)));
+                b.map(|ip| result.push(IpAddr::V4(*ip)));
+            }
+        }
+    }
+    return result;
 }
 
 fn deadline_opt<X, Y>(resp: Response<X, Y>, deadline: Option<Time>)
@@ -253,6 +1192,7 @@ impl<C> Machine for Fsm<C> {
         let deadline = {
             let mut res = self.0.lock().unwrap();
             res.recv_messages(scope);
+            res.poll_tcp(scope);
             res.refresh_timeouts(scope)
         };
         deadline_opt(Response::ok(self), deadline)
@@ -263,13 +1203,296 @@ impl<C> Machine for Fsm<C> {
     fn timeout(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
-        let deadline = self.0.lock().unwrap().refresh_timeouts(scope);
+        let deadline = {
+            let mut res = self.0.lock().unwrap();
+            res.refresh_timeouts(scope)
+        };
         deadline_opt(Response::ok(self), deadline)
     }
     fn wakeup(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
-        let deadline = self.0.lock().unwrap().refresh_timeouts(scope);
+        let deadline = {
+            let mut res = self.0.lock().unwrap();
+            res.refresh_timeouts(scope)
+        };
         deadline_opt(Response::ok(self), deadline)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv6Addr, TcpListener};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use rotor::Time;
+    use {Config, ServerSelection, Query, QueryOptions, Answer};
+    use mock::{Mock, Server, reply, result, query_type};
+    use wire;
+    use super::attempt_deadline;
+
+    const A: u16 = 1;
+    const AAAA: u16 = 28;
+    /// Numbers in the response are aligned for this name, see `mock`
+    const NAME: &'static str = "examples.com";
+    const IPV6: &'static [u8] = &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                                  0, 0, 0, 0, 0, 0, 0, 1];
+
+    fn secs(n: u64) -> Duration { Duration::new(n, 0) }
+
+    fn config(servers: &[&Server]) -> Config {
+        Config::new(servers.iter().map(|x| x.address()).collect())
+    }
+
+    fn no_data(value: Option<Answer>) {
+        match value {
+            Some(Answer::NoData) => {}
+            value => panic!("Unexpected answer {:?}", value),
+        }
+    }
+
+    #[test]
+    fn no_aaaa_ipv6() {
+        let server = Server::new();
+        let mut cfg = config(&[&server]);
+        cfg.no_aaaa = true;
+        let mut mock = Mock::new(cfg);
+        let slot = mock.query(Query::LookupIpv6(NAME.into()));
+        no_data(result(&slot));
+        assert_eq!(mock.client_wakeups(), 1);
+        assert!(server.recv().is_none());
+    }
+
+    #[test]
+    fn no_aaaa_host() {
+        let server = Server::new();
+        let mut cfg = config(&[&server]);
+        cfg.no_aaaa = true;
+        let mut mock = Mock::new(cfg);
+        let slot = mock.query(Query::LookupHost(NAME.into()));
+        let query = server.recv().unwrap();
+        assert_eq!(query_type(&query), A);
+        assert!(server.recv().is_none());
+        server.send(&reply(&query, &[]), &mock);
+        mock.ready();
+        no_data(result(&slot));
+    }
+
+    #[test]
+    fn rotate() {
+        let servers = [Server::new(), Server::new()];
+        let mut cfg = config(&[&servers[0], &servers[1]]);
+        cfg.selection = ServerSelection::Rotate;
+        let mut mock = Mock::new(cfg);
+        mock.query(Query::LookupIpv4("a.example.com".into()));
+        mock.query(Query::LookupIpv4("b.example.com".into()));
+        let name = |server: &Server| {
+            wire::question(&server.recv().unwrap()).unwrap().0
+        };
+        assert_eq!(name(&servers[1]), "a.example.com");
+        assert_eq!(name(&servers[0]), "b.example.com");
+        assert!(servers[0].recv().is_none());
+        assert!(servers[1].recv().is_none());
+    }
+
+    #[test]
+    fn single_request() {
+        let server = Server::new();
+        let mut cfg = config(&[&server]);
+        cfg.single_request = true;
+        let mut mock = Mock::new(cfg);
+        let slot = mock.query(Query::LookupHost(NAME.into()));
+        let query = server.recv().unwrap();
+        assert_eq!(query_type(&query), A);
+        assert!(server.recv().is_none());
+        server.send(&reply(&query, &[]), &mock);
+        mock.ready();
+        assert!(result(&slot).is_none());
+        assert_eq!(mock.client_wakeups(), 0);
+        let query = server.recv().unwrap();
+        assert_eq!(query_type(&query), AAAA);
+        server.send(&reply(&query, &[(AAAA, IPV6)]), &mock);
+        mock.ready();
+        assert_eq!(mock.client_wakeups(), 1);
+        match result(&slot) {
+            Some(Answer::Addresses(ips)) => {
+                assert_eq!(ips, vec!["2001:db8::1".parse::<IpAddr>()
+                                     .unwrap()]);
+            }
+            value => panic!("Unexpected answer {:?}", value),
+        }
+    }
+
+    #[test]
+    fn use_vc() {
+        let server = Server::new();
+        let listener = TcpListener::bind(server.address()).unwrap();
+        let mut cfg = config(&[&server]);
+        cfg.use_vc = true;
+        let mut mock = Mock::new(cfg);
+        let slot = mock.query(Query::LookupIpv6(NAME.into()));
+        // Connection is opened when the resolver is woken up
+        mock.wakeup();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(secs(1))).unwrap();
+        mock.ready();
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut query = vec![0u8; wire::read_u16(&len, 0) as usize];
+        stream.read_exact(&mut query).unwrap();
+        assert_eq!(query_type(&query), AAAA);
+        assert!(server.recv().is_none());
+
+        let response = reply(&query, &[(AAAA, IPV6)]);
+        stream.write_all(&[(response.len() >> 8) as u8,
+                           response.len() as u8]).unwrap();
+        stream.write_all(&response).unwrap();
+        for _ in 0..100 {
+            mock.ready();
+            if result(&slot).is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        match result(&slot) {
+            Some(Answer::Ipv6(ips)) => {
+                assert_eq!(ips, vec!["2001:db8::1".parse::<Ipv6Addr>()
+                                     .unwrap()]);
+            }
+            value => panic!("Unexpected answer {:?}", value),
+        }
+    }
+
+    #[test]
+    fn use_vc_after_timeout() {
+        let server = Server::new();
+        let listener = TcpListener::bind(server.address()).unwrap();
+        let mut cfg = config(&[&server]);
+        cfg.use_vc = true;
+        cfg.single_request = true;
+        cfg.attempts = 1;
+        let timeout = cfg.timeout;
+        let mut mock = Mock::new(cfg);
+        mock.query(Query::LookupHost(NAME.into()));
+        mock.wakeup();
+        let _first = listener.accept().unwrap();
+        // A part fails, so AAAA part is started when handling the timeout
+        mock.sleep(timeout + Duration::from_millis(1));
+        mock.timeout();
+        {
+            let machine = mock.machine.lock().unwrap();
+            assert!(machine.pending_tcp.is_empty());
+            assert_eq!(machine.running.len(), 1);
+            let req = machine.running.values().next().unwrap();
+            assert_eq!(req.query, Query::LookupIpv6(NAME.into()));
+            assert!(req.tcp.is_some());
+        }
+        listener.accept().unwrap();
+    }
+
+    fn unavailable(value: Option<Answer>) {
+        match value {
+            Some(Answer::ServerUnavailable) => {}
+            value => panic!("Unexpected answer {:?}", value),
+        }
+    }
+
+    /// Returns attempt number and deadline of the only running request
+    fn attempt(mock: &Mock) -> (u32, Time) {
+        let machine = mock.machine.lock().unwrap();
+        assert_eq!(machine.running.len(), 1);
+        let req = machine.running.values().next().unwrap();
+        (req.attempts, req.deadline)
+    }
+
+    #[test]
+    fn retry_on_timeout() {
+        let servers = [Server::new(), Server::new()];
+        let mut cfg = config(&[&servers[0], &servers[1]]);
+        cfg.timeout = secs(2);
+        cfg.attempts = 3;
+        let mut mock = Mock::new(cfg);
+        let slot = mock.query(Query::LookupIpv4(NAME.into()));
+        assert!(servers[0].recv().is_some());
+        assert_eq!(attempt(&mock), (1, mock.now + secs(2)));
+
+        mock.sleep(Duration::from_millis(2001));
+        mock.timeout();
+        assert!(servers[1].recv().is_some());
+        assert_eq!(attempt(&mock), (2, mock.now + secs(2)));
+
+        // Second round through the nameservers waits twice as long
+        mock.sleep(Duration::from_millis(2001));
+        mock.timeout();
+        assert!(servers[0].recv().is_some());
+        assert_eq!(attempt(&mock), (3, mock.now + secs(4)));
+        assert!(result(&slot).is_none());
+
+        mock.sleep(Duration::from_millis(4001));
+        mock.timeout();
+        unavailable(result(&slot));
+        assert!(mock.machine.lock().unwrap().running.is_empty());
+        assert!(servers[0].recv().is_none());
+        assert!(servers[1].recv().is_none());
+    }
+
+    #[test]
+    fn query_timeout() {
+        let server = Server::new();
+        let mut cfg = config(&[&server]);
+        cfg.timeout = secs(2);
+        cfg.attempts = 5;
+        let mut mock = Mock::new(cfg);
+        let expires = mock.now + secs(3);
+        let slot = mock.query_with(Query::LookupIpv4(NAME.into()),
+            QueryOptions::new().query_timeout(secs(3)));
+        assert_eq!(attempt(&mock), (1, mock.now + secs(2)));
+
+        // Attempt never outlives the query
+        mock.sleep(Duration::from_millis(2001));
+        mock.timeout();
+        assert_eq!(attempt(&mock), (2, expires));
+        assert!(result(&slot).is_none());
+
+        mock.sleep(secs(1));
+        mock.timeout();
+        unavailable(result(&slot));
+        assert!(mock.machine.lock().unwrap().running.is_empty());
+    }
+
+    #[test]
+    fn doubled_per_round() {
+        let now = Instant::now();
+        let deadline = |attempts| {
+            attempt_deadline(now, secs(2), attempts, 2, None) - now
+        };
+        assert_eq!(deadline(1), secs(2));
+        assert_eq!(deadline(2), secs(2));
+        assert_eq!(deadline(3), secs(4));
+        assert_eq!(deadline(4), secs(4));
+        assert_eq!(deadline(5), secs(8));
+    }
+
+    #[test]
+    fn capped() {
+        let now = Instant::now();
+        assert_eq!(attempt_deadline(now, secs(5), 4, 1, None) - now,
+                   secs(30));
+        assert_eq!(attempt_deadline(now, secs(5), 100, 1, None) - now,
+                   secs(30));
+        // Configured timeout larger than the cap is used as is
+        assert_eq!(attempt_deadline(now, secs(40), 3, 1, None) - now,
+                   secs(40));
+    }
+
+    #[test]
+    fn clamped_to_expires() {
+        let now = Instant::now();
+        let expires = Some(now + secs(3));
+        assert_eq!(attempt_deadline(now, secs(5), 1, 1, expires) - now,
+                   secs(3));
+        assert_eq!(attempt_deadline(now, secs(1), 1, 1, expires) - now,
+                   secs(1));
+    }
+}
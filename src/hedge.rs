@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use rotor::Time;
+
+use {DnsMachine, Request, Hedge, Id, TimeEntry};
+
+
+impl Request {
+    /// Returns nameserver the copy of request with this id was sent to
+    pub fn server_for(&self, id: Id) -> Option<SocketAddr> {
+        if id == self.id {
+            return Some(self.server);
+        }
+        self.hedges.iter().find(|h| h.id == id).map(|h| h.server)
+    }
+    /// Makes the nameserver that answered the copy of the request current
+    ///
+    /// So statistics, retries and TCP fallback use that server.
+    pub fn use_copy(&mut self, id: Id) {
+        if let Some(hedge) = self.hedges.iter().find(|h| h.id == id) {
+            self.nameserver_index = hedge.nameserver_index;
+            self.server = hedge.server;
+            self.sent = hedge.sent;
+        }
+    }
+}
+
+impl DnsMachine {
+    /// Sends copies of the request to other nameservers if configured
+    ///
+    /// Must be called after the request is sent over UDP
+    pub fn start_hedging(&mut self, req: &mut Request, now: Time) {
+        for _ in 1..self.config.parallel {
+            self.send_hedge(req);
+        }
+        if let Some(delay) = self.config.hedge_delay {
+            req.hedge_at = Some(now + delay);
+            self.timeouts.push(TimeEntry(now + delay, req.id));
+        }
+    }
+    /// Sends a copy of the request to the next nameserver in the list
+    /// which has not been queried by this attempt yet
+    pub fn send_hedge(&mut self, req: &mut Request) {
+        let len = self.config.nameservers.len();
+        let last = req.hedges.last().map(|h| h.nameserver_index)
+            .unwrap_or(req.nameserver_index);
+        let idx = (last + 1) % len;
+        if idx == req.nameserver_index ||
+            req.hedges.iter().any(|h| h.nameserver_index == idx)
+        {
+            // All nameservers are already queried
+            return;
+        }
+        let server = self.config.nameservers[idx];
+        let id = self.new_id();
+        let pack = match self.build_request(req, id) {
+            Ok(pack) => pack,
+            Err(_) => return,
+        };
+        // Failed copy is not a big deal, as the original is still in flight
+        if self.sock.send_to(&pack, &server).is_err() {
+            return;
+        }
+        req.hedges.push(Hedge {
+            id: id,
+            nameserver_index: idx,
+            server: server,
+            sent: Instant::now(),
+        });
+        self.hedged.insert(id, req.id);
+    }
+    /// Forgets about copies of the request sent to other nameservers
+    pub fn clear_hedges(&mut self, req: &mut Request) {
+        for hedge in req.hedges.drain(..) {
+            self.hedged.remove(&hedge.id);
+        }
+        req.hedge_at = None;
+    }
+    /// Stops waiting for a single copy of the request, e.g. when its
+    /// nameserver returned SERVFAIL, and puts request back
+    pub fn drop_copy(&mut self, mut req: Request, id: Id) {
+        if id == req.id && req.hedges.len() > 0 {
+            // Next copy becomes the primary one
+            let hedge = req.hedges.remove(0);
+            self.hedged.remove(&hedge.id);
+            req.id = hedge.id;
+            req.nameserver_index = hedge.nameserver_index;
+            req.server = hedge.server;
+            req.sent = hedge.sent;
+            for hedge in &req.hedges {
+                self.hedged.insert(hedge.id, req.id);
+            }
+            self.timeouts.push(TimeEntry(req.deadline, req.id));
+            req.hedge_at.map(|t| self.timeouts.push(TimeEntry(t, req.id)));
+            self.queued.insert(req.key(), req.id);
+        } else {
+            req.hedges.retain(|h| h.id != id);
+            self.hedged.remove(&id);
+        }
+        self.running.insert(req.id, req);
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+use std::io::Read;
+use std::fs::File;
+use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+
+
+/// Static host names table, as in `/etc/hosts` (see `hosts(5)`)
+#[derive(Debug, Default)]
+pub struct Hosts {
+    ipv4: HashMap<String, Vec<Ipv4Addr>>,
+    ipv6: HashMap<String, Vec<Ipv6Addr>>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_right_matches('.').to_lowercase()
+}
+
+impl Hosts {
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Hosts> {
+        let mut buf = String::with_capacity(1024);
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut buf)));
+        Ok(Hosts::parse(&buf))
+    }
+    /// Parses the hosts file, skipping invalid lines like glibc does
+    pub fn parse(data: &str) -> Hosts {
+        let mut hosts = Hosts::default();
+        for line in data.lines() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            };
+            let mut words = line.split_whitespace();
+            let addr = match words.next().and_then(|x| x.parse().ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            for name in words {
+                match addr {
+                    IpAddr::V4(ip) => {
+                        hosts.ipv4.entry(normalize(name))
+                            .or_insert_with(Vec::new).push(ip);
+                    }
+                    IpAddr::V6(ip) => {
+                        hosts.ipv6.entry(normalize(name))
+                            .or_insert_with(Vec::new).push(ip);
+                    }
+                }
+            }
+        }
+        return hosts;
+    }
+    pub fn ipv4(&self, name: &str) -> Option<&Vec<Ipv4Addr>> {
+        self.ipv4.get(&normalize(name))
+    }
+    pub fn ipv6(&self, name: &str) -> Option<&Vec<Ipv6Addr>> {
+        self.ipv6.get(&normalize(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use super::Hosts;
+
+    #[test]
+    fn parse() {
+        let hosts = Hosts::parse("\
+            127.0.0.1 localhost\n\
+            ::1\tlocalhost ip6-localhost # loopback\n\
+            10.0.0.1 Web.Example.com. web\n\
+            10.0.0.2 web\n");
+        assert_eq!(hosts.ipv4("localhost"),
+            Some(&vec![Ipv4Addr::new(127, 0, 0, 1)]));
+        assert_eq!(hosts.ipv6("ip6-localhost"),
+            Some(&vec![Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)]));
+        assert_eq!(hosts.ipv4("web"), Some(&vec![
+            Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]));
+        assert_eq!(hosts.ipv6("web"), None);
+    }
+
+    #[test]
+    fn case_and_trailing_dot() {
+        let hosts = Hosts::parse("10.0.0.1 Web.Example.com.\n");
+        let ips = Some(&vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        assert_eq!(hosts.ipv4("web.example.com"), ips);
+        assert_eq!(hosts.ipv4("WEB.EXAMPLE.COM."), ips);
+    }
+
+    #[test]
+    fn comments_and_invalid_lines() {
+        let hosts = Hosts::parse("\
+            # 10.0.0.1 commented\n\
+            \n\
+            not-an-ip name\n\
+            10.0.0.300 bad\n\
+            10.0.0.2#nospace\n\
+            10.0.0.3 good # 10.0.0.4 trailing\n");
+        assert_eq!(hosts.ipv4("commented"), None);
+        assert_eq!(hosts.ipv4("name"), None);
+        assert_eq!(hosts.ipv4("bad"), None);
+        assert_eq!(hosts.ipv4("nospace"), None);
+        assert_eq!(hosts.ipv4("trailing"), None);
+        assert_eq!(hosts.ipv4("good"),
+            Some(&vec![Ipv4Addr::new(10, 0, 0, 3)]));
+    }
+}
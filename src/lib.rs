@@ -4,22 +4,40 @@ extern crate dns_parser;
 extern crate resolv_conf;
 #[macro_use] extern crate quick_error;
 
+mod cache;
+mod cancel;
 mod config;
 mod fsm;
+mod hedge;
+mod hosts;
+#[cfg(test)] mod mock;
+mod raw;
+mod reload;
 mod resolver;
+mod servers;
+mod tcp;
+mod wire;
 mod time_util;
 
+use std::io;
+use std::borrow::Cow;
 use std::marker::PhantomData;
 use std::collections::{HashMap, BinaryHeap};
 use std::sync::{Arc, Mutex};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 
-use rotor::{EarlyScope, PollOpt, EventSet, Notifier, Time, Response, Void};
+use rotor::{EarlyScope, PollOpt, EventSet, Notifier, Time};
+use rotor::{Response, Void};
 use rotor::mio::udp::UdpSocket;
 
-pub use config::Config;
+pub use config::{Config, ServerSelection};
+pub use servers::NameserverStats;
+use hosts::Hosts;
+use cache::Cache;
+pub use cancel::Slot;
 pub use resolver::QueryError;
-pub use dns_parser::QueryType;
+pub use dns_parser::{QueryType, QueryClass, ResponseCode};
 
 type Id = u16;
 #[derive(Debug)]
@@ -27,23 +45,215 @@ struct TimeEntry(Time, Id);
 
 
 /// Human friendly query types
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Query {
     /// Simple host lookup (A record)
     LookupIpv4(String),
+    /// Simple IPv6 host lookup (AAAA record)
+    LookupIpv6(String),
+    /// Dual-stack host lookup (A and AAAA records queried in parallel)
+    ///
+    /// Addresses are interleaved by family, IPv6 first, as recommended
+    /// by RFC 6724 and RFC 8305 (Happy Eyeballs)
+    LookupHost(String),
     /// Plain SRV record lookup
     LookupSrv(String),
     /// Plain MX record lookup
     LookupMx(String),
+    /// Plain TXT record lookup
+    LookupTxt(String),
+    /// Plain PTR record lookup
+    LookupPtr(String),
+    /// Authoritative nameservers of the zone (NS records)
+    LookupNs(String),
+    /// Start of authority record of the zone
+    LookupSoa(String),
+    /// Certification authorities allowed to issue certificates (RFC 8659)
+    LookupCaa(String),
+    /// TLS certificate associations for DANE (RFC 6698), the name is
+    /// like `_443._tcp.example.com`
+    LookupTlsa(String),
+    /// SSH host key fingerprints (RFC 4255)
+    LookupSshfp(String),
+    /// Names of the address (PTR records in `in-addr.arpa` or `ip6.arpa`)
+    ///
+    /// It's resolved as `LookupPtr` of the `Query::reverse_name(ip)`
+    ReverseLookup(IpAddr),
+    /// Query of any type and class, always answered with `Answer::Raw`
+    /// when nameserver responds with NOERROR or NXDOMAIN
+    ///
+    /// Use `QueryType` and `QueryClass` values casted to `u16` for the
+    /// types known to `dns_parser`
+    Raw { name: String, qtype: u16, qclass: u16 },
+}
+
+impl Query {
+    /// Domain name that is queried
+    ///
+    /// For `ReverseLookup` it's the `Query::reverse_name` of the address
+    pub fn name(&self) -> Cow<str> {
+        let name = match *self {
+            Query::LookupIpv4(ref name) => name,
+            Query::LookupIpv6(ref name) => name,
+            Query::LookupHost(ref name) => name,
+            Query::LookupSrv(ref name) => name,
+            Query::LookupMx(ref name) => name,
+            Query::LookupTxt(ref name) => name,
+            Query::LookupPtr(ref name) => name,
+            Query::LookupNs(ref name) => name,
+            Query::LookupSoa(ref name) => name,
+            Query::LookupCaa(ref name) => name,
+            Query::LookupTlsa(ref name) => name,
+            Query::LookupSshfp(ref name) => name,
+            Query::Raw { ref name, .. } => name,
+            Query::ReverseLookup(ip) => {
+                return Cow::Owned(Query::reverse_name(ip));
+            }
+        };
+        Cow::Borrowed(name)
+    }
+    /// Returns same kind of query for another domain name
+    ///
+    /// `ReverseLookup` is queried by name, so it's turned into
+    /// `LookupPtr` of that name
+    pub fn with_name(&self, name: String) -> Query {
+        match *self {
+            Query::LookupIpv4(_) => Query::LookupIpv4(name),
+            Query::LookupIpv6(_) => Query::LookupIpv6(name),
+            Query::LookupHost(_) => Query::LookupHost(name),
+            Query::LookupSrv(_) => Query::LookupSrv(name),
+            Query::LookupMx(_) => Query::LookupMx(name),
+            Query::LookupTxt(_) => Query::LookupTxt(name),
+            Query::LookupPtr(_) => Query::LookupPtr(name),
+            Query::LookupNs(_) => Query::LookupNs(name),
+            Query::LookupSoa(_) => Query::LookupSoa(name),
+            Query::LookupCaa(_) => Query::LookupCaa(name),
+            Query::LookupTlsa(_) => Query::LookupTlsa(name),
+            Query::LookupSshfp(_) => Query::LookupSshfp(name),
+            Query::ReverseLookup(_) => Query::LookupPtr(name),
+            Query::Raw { qtype, qclass, .. } => Query::Raw {
+                name: name,
+                qtype: qtype,
+                qclass: qclass,
+            },
+        }
+    }
+    /// Type of the query built and parsed by `dns_parser`
+    ///
+    /// It's `None` for the queries which are never sent as is and for the
+    /// ones handled without `dns_parser`, see `raw_type`
+    fn query_type(&self) -> Option<QueryType> {
+        match *self {
+            Query::LookupIpv4(_) => Some(QueryType::A),
+            Query::LookupIpv6(_) => Some(QueryType::AAAA),
+            Query::LookupSrv(_) => Some(QueryType::SRV),
+            Query::LookupMx(_) => Some(QueryType::MX),
+            Query::LookupTxt(_) => Some(QueryType::TXT),
+            Query::LookupPtr(_) => Some(QueryType::PTR),
+            Query::LookupNs(_) => Some(QueryType::NS),
+            Query::LookupSoa(_) => Some(QueryType::SOA),
+            _ => None,
+        }
+    }
+    /// Type and class of the queries answered from the records as is,
+    /// without `dns_parser`
+    fn raw_type(&self) -> Option<(u16, u16)> {
+        match *self {
+            Query::Raw { qtype, qclass, .. } => Some((qtype, qclass)),
+            Query::LookupCaa(_) => Some((wire::CAA_TYPE, wire::IN_CLASS)),
+            Query::LookupTlsa(_) => Some((wire::TLSA_TYPE, wire::IN_CLASS)),
+            Query::LookupSshfp(_) => {
+                Some((wire::SSHFP_TYPE, wire::IN_CLASS))
+            }
+            _ => None,
+        }
+    }
+    /// Returns the name of PTR record for the address, e.g.
+    /// `4.3.2.1.in-addr.arpa` for `1.2.3.4` (IPv6 addresses are written
+    /// as nibbles in `ip6.arpa`, see RFC 3596)
+    pub fn reverse_name(ip: IpAddr) -> String {
+        let mut name = String::with_capacity(72);
+        match ip {
+            IpAddr::V4(ip) => {
+                for octet in ip.octets().iter().rev() {
+                    name.push_str(&format!("{}.", octet));
+                }
+                name.push_str("in-addr.arpa");
+            }
+            IpAddr::V6(ip) => {
+                for segment in ip.segments().iter().rev() {
+                    for nibble in 0..4 {
+                        name.push_str(
+                            &format!("{:x}.", (segment >> nibble * 4) & 0xF));
+                    }
+                }
+                name.push_str("ip6.arpa");
+            }
+        }
+        name
+    }
 }
 
 /// A generic DNS answer
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Answer {
     ServerUnavailable,
+    /// Domain name doesn't exist
+    NxDomain,
+    /// Domain name exists but has no records of the requested type
+    NoData,
+    /// Nameserver returned an error response code
+    Error(ResponseCode),
+    /// Chain of CNAME records is looped or too long
+    CnameLoop,
     Ipv4(Vec<Ipv4Addr>),
+    Ipv6(Vec<Ipv6Addr>),
+    Addresses(Vec<IpAddr>),
     Srv(Vec<SrvRecord>),
     Mx(Vec<MxRecord>),
+    /// TXT records, each one is a list of character strings as is
+    Txt(Vec<Vec<Vec<u8>>>),
+    /// Targets of PTR records
+    Ptr(Vec<String>),
+    Ns(Vec<NsRecord>),
+    Soa(Vec<SoaRecord>),
+    Caa(Vec<CaaRecord>),
+    Tlsa(Vec<TlsaRecord>),
+    Sshfp(Vec<SshfpRecord>),
+    /// Response to the `Query::Raw`, including the empty ones
+    Raw(RawAnswer),
+}
+
+impl Answer {
+    /// Returns true if answer contains no records
+    ///
+    /// `Answer::Raw` is not empty if it has records in any section, e.g.
+    /// a referral has only authority and additional records
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            Answer::ServerUnavailable => true,
+            Answer::NxDomain => true,
+            Answer::NoData => true,
+            Answer::Error(_) => true,
+            Answer::CnameLoop => true,
+            Answer::Ipv4(ref x) => x.is_empty(),
+            Answer::Ipv6(ref x) => x.is_empty(),
+            Answer::Addresses(ref x) => x.is_empty(),
+            Answer::Srv(ref x) => x.is_empty(),
+            Answer::Mx(ref x) => x.is_empty(),
+            Answer::Txt(ref x) => x.is_empty(),
+            Answer::Ptr(ref x) => x.is_empty(),
+            Answer::Ns(ref x) => x.is_empty(),
+            Answer::Soa(ref x) => x.is_empty(),
+            Answer::Caa(ref x) => x.is_empty(),
+            Answer::Tlsa(ref x) => x.is_empty(),
+            Answer::Sshfp(ref x) => x.is_empty(),
+            Answer::Raw(ref x) => {
+                x.answers.is_empty() && x.nameservers.is_empty()
+                && x.additional.is_empty()
+            }
+        }
+    }
 }
 
 /// Single SRV record
@@ -62,50 +272,304 @@ pub struct MxRecord {
     pub exchange: String,
 }
 
+/// Single NS record
+#[derive(Debug, Clone)]
+pub struct NsRecord {
+    pub nameserver: String,
+}
+
+/// Single SOA record (RFC 1035, section 3.3.13)
+#[derive(Debug, Clone)]
+pub struct SoaRecord {
+    /// Primary nameserver of the zone
+    pub mname: String,
+    /// Mailbox of the person responsible for the zone
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// TTL of negative answers (RFC 2308)
+    pub minimum: u32,
+}
+
+/// Single CAA record (RFC 8659)
+#[derive(Debug, Clone)]
+pub struct CaaRecord {
+    /// Bit 128 is the "issuer critical" flag
+    pub flags: u8,
+    /// Property, e.g. `issue`, `issuewild` or `iodef`
+    pub tag: String,
+    pub value: Vec<u8>,
+}
+
+/// Single TLSA record (RFC 6698)
+#[derive(Debug, Clone)]
+pub struct TlsaRecord {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching_type: u8,
+    /// Certificate or public key, or its hash
+    pub data: Vec<u8>,
+}
+
+/// Single SSHFP record (RFC 4255)
+#[derive(Debug, Clone)]
+pub struct SshfpRecord {
+    pub algorithm: u8,
+    pub fingerprint_type: u8,
+    pub fingerprint: Vec<u8>,
+}
+
+/// Resource record as it's received, see `Query::Raw`
+///
+/// Names in the `data` of the record types defined in RFC 1035 are
+/// decompressed, so the data can be parsed without the whole packet
+#[derive(Debug, Clone)]
+pub struct RawRecord {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+/// All sections of the response, see `Query::Raw`
+#[derive(Debug, Clone)]
+pub struct RawAnswer {
+    /// Either `NoError` or `NameError` (for NXDOMAIN)
+    pub response_code: ResponseCode,
+    pub answers: Vec<RawRecord>,
+    /// Authority section
+    pub nameservers: Vec<RawRecord>,
+    pub additional: Vec<RawRecord>,
+}
+
+/// Options of a single query, see `Resolver::query_with`
+///
+/// Only queries with equal options share requests in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryOptions {
+    timeout: Option<Duration>,
+    query_timeout: Option<Duration>,
+    use_cache: bool,
+    populate_cache: bool,
+    recursion: bool,
+}
+
+impl QueryOptions {
+    /// Options used by `Resolver::query`
+    pub fn new() -> QueryOptions {
+        QueryOptions {
+            timeout: None,
+            query_timeout: None,
+            use_cache: true,
+            populate_cache: true,
+            recursion: true,
+        }
+    }
+    /// Timeout of the first round of attempts, overrides `Config::timeout`
+    pub fn timeout(mut self, timeout: Duration) -> QueryOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Deadline of the query including all retries, counted from the
+    /// moment query is started, overrides `Config::query_timeout`
+    pub fn query_timeout(mut self, timeout: Duration) -> QueryOptions {
+        self.query_timeout = Some(timeout);
+        self
+    }
+    /// Whether answer may be returned from the cache (default `true`)
+    pub fn use_cache(mut self, value: bool) -> QueryOptions {
+        self.use_cache = value;
+        self
+    }
+    /// Whether answer is stored in the cache (default `true`)
+    pub fn populate_cache(mut self, value: bool) -> QueryOptions {
+        self.populate_cache = value;
+        self
+    }
+    /// Sets the "recursion desired" flag of the request (default `true`)
+    ///
+    /// Answers to non-recursive queries never come from or go to the
+    /// cache, as they are not complete answers usually.
+    pub fn recursion(mut self, value: bool) -> QueryOptions {
+        self.recursion = value;
+        self
+    }
+    fn reads_cache(&self) -> bool {
+        self.use_cache && self.recursion
+    }
+    fn writes_cache(&self) -> bool {
+        self.populate_cache && self.recursion
+    }
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions::new()
+    }
+}
+
+/// Someone who waits for the result of a request
+enum Waiter {
+    /// A state machine which is woken up when result is put into the slot
+    Machine(Slot, Notifier),
+    /// IPv4 part of the `Query::LookupHost` for this name
+    HostIpv4(String, QueryOptions),
+    /// IPv6 part of the `Query::LookupHost` for this name
+    HostIpv6(String, QueryOptions),
+    /// One of the names tried for the query using search list
+    Search(Query, QueryOptions),
+}
+
+/// A query for relative name which tries names from the search list
+struct SearchLookup {
+    /// Names left to try, the last one is tried first
+    candidates: Vec<String>,
+    got_nodata: bool,
+    notifiers: Vec<Waiter>,
+}
+
+/// The `Query::LookupHost` which waits for both of its parts
+struct HostLookup {
+    ipv4: Option<Arc<CacheEntry>>,
+    ipv6: Option<Arc<CacheEntry>>,
+    /// Part which is sent when first one is done, in single-request mode
+    next: Option<Query>,
+    /// AAAA part is not sent because of `no_aaaa` option
+    no_aaaa: bool,
+    notifiers: Vec<Waiter>,
+}
+
 struct Request {
     id: Id,
     query: Query,
+    options: QueryOptions,
     nameserver_index: usize,
     attempts: u32,
     server: SocketAddr,
+    /// When the last packet was sent, to measure response time
+    sent: Instant,
+    /// When the current attempt times out
     deadline: Time,
-    notifiers: Vec<(Arc<Mutex<Option<Arc<CacheEntry>>>>, Notifier)>,
+    /// Overall deadline of the query including all retries
+    expires: Option<Time>,
+    notifiers: Vec<Waiter>,
+    /// Error code of the last server failure (SERVFAIL, REFUSED)
+    last_error: Option<ResponseCode>,
+    /// CNAME chain followed so far, the last one is queried now
+    aliases: Vec<String>,
+    /// Cleared if nameserver doesn't support EDNS
+    edns: bool,
+    /// Set when UDP response was truncated, all retries go over TCP too
+    use_tcp: bool,
+    tcp: Option<tcp::Connection>,
+    /// Copies of the request sent to other nameservers
+    hedges: Vec<Hedge>,
+    /// When to send the next copy of the request
+    hedge_at: Option<Time>,
+}
+
+/// Copy of the request sent to another nameserver in parallel
+struct Hedge {
+    id: Id,
+    nameserver_index: usize,
+    server: SocketAddr,
+    sent: Instant,
 }
 
 #[derive(Debug)]
 pub struct CacheEntry {
     pub value: Answer,
     pub expire: Time,
+    /// Target of the CNAME chain, if the queried name is an alias
+    pub canonical_name: Option<String>,
+    /// Fully qualified name that was queried, it's where the search list
+    /// lookup stopped for relative names
+    pub name: String,
+    /// Answer is taken from the expired cache entry, because nameservers
+    /// are unreachable (RFC 8767)
+    pub stale: bool,
 }
 
 struct DnsMachine {
     config: Config,
     running: HashMap<Id, Request>,
-    cache: HashMap<Query, Arc<CacheEntry>>,
+    /// Ids of the request copies mapped to the ids of original requests
+    hedged: HashMap<Id, Id>,
+    /// Requests in flight by query, to attach duplicate queries to them
+    queued: HashMap<(Query, QueryOptions), Id>,
+    host_lookups: HashMap<(String, QueryOptions), HostLookup>,
+    searches: HashMap<(Query, QueryOptions), SearchLookup>,
+    cache: Cache,
+    /// When expired entries are removed from the cache next time
+    next_sweep: Time,
+    hosts: Hosts,
+    next_reload: Option<Time>,
+    resolv_conf_mtime: Option<SystemTime>,
+    hosts_mtime: Option<SystemTime>,
     sock: UdpSocket,
     timeouts: BinaryHeap<TimeEntry>,
+    /// Requests waiting for TCP connection to be opened
+    pending_tcp: Vec<Id>,
+    /// Counter for the `ServerSelection::Rotate`
+    rotation: usize,
+    servers: HashMap<SocketAddr, NameserverStats>,
     notifier: Notifier,
 }
 
 pub struct Fsm<C>(Arc<Mutex<DnsMachine>>, PhantomData<*const C>);
 pub struct Resolver(Arc<Mutex<DnsMachine>>);
 
+impl DnsMachine {
+    fn new(config: Config, now: Time, notifier: Notifier)
+        -> io::Result<DnsMachine>
+    {
+        // Missing hosts file is not an error, like in glibc
+        let hosts = config.hosts_file.as_ref()
+            .and_then(|path| Hosts::read(path).ok())
+            .unwrap_or_else(Hosts::default);
+        let resolv_conf_mtime = config.resolv_conf.as_ref()
+            .and_then(|path| reload::modified(path));
+        let hosts_mtime = config.hosts_file.as_ref()
+            .and_then(|path| reload::modified(path));
+        let next_reload = config.reload_interval.map(|x| now + x);
+        let cache = Cache::new(config.cache_size,
+            config.serve_stale.unwrap_or(Duration::new(0, 0)));
+        let sock = try!(UdpSocket::bound(&SocketAddr::V4(
+            SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))));
+        Ok(DnsMachine {
+            config: config,
+            running: HashMap::new(),
+            hedged: HashMap::new(),
+            queued: HashMap::new(),
+            host_lookups: HashMap::new(),
+            searches: HashMap::new(),
+            cache: cache,
+            next_sweep: now,
+            hosts: hosts,
+            next_reload: next_reload,
+            resolv_conf_mtime: resolv_conf_mtime,
+            hosts_mtime: hosts_mtime,
+            sock: sock,
+            timeouts: BinaryHeap::new(),
+            pending_tcp: Vec::new(),
+            rotation: 0,
+            servers: HashMap::new(),
+            notifier: notifier,
+        })
+    }
+}
+
 pub fn create_resolver<C>(scope: &mut EarlyScope, config: Config)
     -> Response<(Fsm<C>, Resolver), Void>
 {
-    let machine = DnsMachine {
-        config: config,
-        running: HashMap::new(),
-        // TODO(tailhook) implement duplicate checking
-        // queued: HashMap::new(),
-        cache: HashMap::new(),
-        sock: match UdpSocket::bound(&SocketAddr::V4(
-            SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))) {
-            Ok(sock) => sock,
-            Err(e) => return Response::error(Box::new(e)),
-        },
-        timeouts: BinaryHeap::new(),
-        notifier: scope.notifier(),
+    let machine = match DnsMachine::new(config, scope.now(),
+                                        scope.notifier())
+    {
+        Ok(machine) => machine,
+        Err(e) => return Response::error(Box::new(e)),
     };
     match scope.register(&machine.sock,
         EventSet::readable(), PollOpt::level())
@@ -116,3 +580,34 @@ pub fn create_resolver<C>(scope: &mut EarlyScope, config: Config)
     let arc = Arc::new(Mutex::new(machine));
     Response::ok((Fsm(arc.clone(), PhantomData), Resolver(arc.clone())))
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use super::Query;
+
+    fn reverse(ip: &str) -> String {
+        Query::reverse_name(ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn reverse_ipv4() {
+        assert_eq!(reverse("192.0.2.1"), "1.2.0.192.in-addr.arpa");
+    }
+
+    #[test]
+    fn reverse_ipv6() {
+        // Example from RFC 3596, section 2.5
+        assert_eq!(reverse("4321:0:1:2:3:4:567:89ab"),
+            "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.\
+             2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa");
+    }
+
+    #[test]
+    fn reverse_lookup_name() {
+        let ip = "10.1.2.3".parse().unwrap();
+        assert_eq!(Query::ReverseLookup(ip).name(), "3.2.1.10.in-addr.arpa");
+        assert_eq!(Query::ReverseLookup(ip).with_name("x".into()),
+                   Query::LookupPtr("x".into()));
+    }
+}
@@ -0,0 +1,206 @@
+//! Drives the resolver in unit tests without the main loop
+//!
+//! Time is set by the test, nameservers are plain sockets on the loopback
+//! interface, and wakeups of the state machines are recorded.
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rotor::{Machine, Time, Scope, Evented, EventSet, PollOpt};
+use rotor::{Timeout, TimerError, _LoopApi, _Notify, _Timeo, _scope};
+use rotor::mio::{self, EventLoop, Sender, Token};
+
+use {DnsMachine, Fsm, Resolver, Config, Query, QueryOptions, Answer};
+use cancel::Slot;
+use wire;
+
+/// Token of the resolver state machine
+const RESOLVER: Token = Token(0);
+/// Token of the state machine which makes queries
+const CLIENT: Token = Token(1);
+
+
+/// Loop which ignores all the sockets, they are polled by the test
+struct Loop;
+
+/// Counts notifications of the client state machine
+struct Recorder {
+    wakeups: usize,
+}
+
+pub struct Mock {
+    pub machine: Arc<Mutex<DnsMachine>>,
+    pub now: Time,
+    eloop: EventLoop<Recorder>,
+    channel: Sender<_Notify>,
+}
+
+impl _LoopApi for Loop {
+    fn register(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn reregister(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn deregister(&mut self, _io: &Evented) -> io::Result<()> {
+        Ok(())
+    }
+    fn timeout_ms(&mut self, _token: Token, _delay: u64)
+        -> Result<Timeout, TimerError>
+    {
+        unimplemented!();
+    }
+    fn clear_timeout(&mut self, _token: Timeout) -> bool {
+        unimplemented!();
+    }
+    fn shutdown(&mut self) {}
+}
+
+impl mio::Handler for Recorder {
+    type Timeout = _Timeo;
+    type Message = _Notify;
+    fn notify(&mut self, _eloop: &mut EventLoop<Recorder>, msg: _Notify) {
+        match msg {
+            _Notify::Fsm(token) if token == CLIENT => self.wakeups += 1,
+            _Notify::Fsm(_) => {}
+        }
+    }
+}
+
+impl Mock {
+    pub fn new(config: Config) -> Mock {
+        let eloop = EventLoop::new().unwrap();
+        let mut channel = eloop.channel();
+        let now = Time::zero();
+        let notifier = _scope(now, RESOLVER, &mut (), &mut channel,
+                              &mut Loop).notifier();
+        let machine = DnsMachine::new(config, now, notifier).unwrap();
+        Mock {
+            machine: Arc::new(Mutex::new(machine)),
+            now: now,
+            eloop: eloop,
+            channel: channel,
+        }
+    }
+    /// Moves the clock forward
+    pub fn sleep(&mut self, duration: Duration) {
+        self.now = self.now + duration;
+    }
+    fn run<F, R>(&mut self, token: Token, f: F) -> R
+        where F: FnOnce(&mut Scope<()>) -> R
+    {
+        f(&mut _scope(self.now, token, &mut (), &mut self.channel,
+                      &mut Loop))
+    }
+    pub fn query(&mut self, query: Query) -> Slot {
+        self.query_with(query, QueryOptions::new())
+    }
+    pub fn query_with(&mut self, query: Query, options: QueryOptions)
+        -> Slot
+    {
+        let resolver = Resolver(self.machine.clone());
+        self.run(CLIENT, |scope| {
+            resolver.query_with::<Scope<()>>(query, options, scope)
+        }).unwrap()
+    }
+    pub fn cancel(&mut self, slot: &Slot) {
+        Resolver(self.machine.clone()).cancel(slot);
+    }
+    /// Runs the resolver like the main loop does on socket events
+    pub fn ready(&mut self) {
+        let fsm = Fsm::<()>(self.machine.clone(), PhantomData);
+        self.run(RESOLVER, |scope| {
+            fsm.ready(EventSet::readable(), scope);
+        });
+    }
+    /// Runs the resolver like the main loop does when deadline passes
+    pub fn timeout(&mut self) {
+        let fsm = Fsm::<()>(self.machine.clone(), PhantomData);
+        self.run(RESOLVER, |scope| { fsm.timeout(scope); });
+    }
+    /// Runs the resolver like the main loop does after a query is made
+    pub fn wakeup(&mut self) {
+        let fsm = Fsm::<()>(self.machine.clone(), PhantomData);
+        self.run(RESOLVER, |scope| { fsm.wakeup(scope); });
+    }
+    /// Number of times the client was woken up since the last call
+    pub fn client_wakeups(&mut self) -> usize {
+        let mut recorder = Recorder { wakeups: 0 };
+        self.eloop.run_once(&mut recorder, Some(0)).unwrap();
+        recorder.wakeups
+    }
+    /// Address the nameservers send responses to
+    pub fn address(&self) -> SocketAddr {
+        let port = self.machine.lock().unwrap().sock.local_addr()
+            .unwrap().port();
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+}
+
+/// Nameserver on the loopback interface
+pub struct Server {
+    sock: UdpSocket,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        Server { sock: sock }
+    }
+    pub fn address(&self) -> SocketAddr {
+        self.sock.local_addr().unwrap()
+    }
+    /// Receives a query, returns `None` if there is none
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 4096];
+        match self.sock.recv_from(&mut buf) {
+            Ok((bytes, _)) => Some(buf[..bytes].to_vec()),
+            Err(_) => None,
+        }
+    }
+    pub fn send(&self, pack: &[u8], mock: &Mock) {
+        self.sock.send_to(pack, &mock.address()).unwrap();
+    }
+}
+
+/// Returns type of the question in the query
+pub fn query_type(pack: &[u8]) -> u16 {
+    wire::question(pack).unwrap().1
+}
+
+/// Builds a response to the query with answers for the queried name
+///
+/// Answers are given as record type and data, TTL is one hour.
+///
+/// Note `dns_parser` reads numbers by pointers which must be aligned in
+/// debug builds. So the length of the encoded name should be `4*N + 2`
+/// (like `examples.com`) for the TTL to be aligned, and A records can't
+/// be used at all, because their data is never aligned then.
+pub fn reply(query: &[u8], answers: &[(u16, &[u8])]) -> Vec<u8> {
+    let end = wire::skip_name(query, wire::HEADER_SIZE).unwrap() + 4;
+    let mut pack = query[..end].to_vec();
+    pack[2] = 0x81;
+    pack[3] = 0x80;
+    pack[6] = 0;
+    pack[7] = answers.len() as u8;
+    pack[8..12].copy_from_slice(&[0, 0, 0, 0]);
+    for &(rtype, data) in answers {
+        pack.extend(&[0xC0, 12, (rtype >> 8) as u8, rtype as u8, 0, 1]);
+        pack.extend(&[0, 0, 0x0E, 0x10]);
+        pack.extend(&[(data.len() >> 8) as u8, data.len() as u8]);
+        pack.extend(data);
+    }
+    pack
+}
+
+/// Returns the answer put into the slot, if any
+pub fn result(slot: &Slot) -> Option<Answer> {
+    slot.lock().unwrap().as_ref().map(|entry| entry.value.clone())
+}
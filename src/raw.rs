@@ -0,0 +1,163 @@
+use std::cmp::min;
+use std::i32;
+
+use rotor::Scope;
+
+use {DnsMachine, Request, Query, Answer, RawRecord};
+use fsm::negative_ttl;
+use wire;
+
+/// The CNAME record type
+const CNAME_TYPE: u16 = 5;
+/// The SOA record type
+const SOA_TYPE: u16 = 6;
+
+
+impl Request {
+    /// Checks the question of the response to the query that is not
+    /// parsed by `dns_parser`, see `Query::raw_type`
+    pub fn matches_raw(&self, data: &[u8]) -> bool {
+        let (qtype, qclass) = match self.query.raw_type() {
+            Some(pair) => pair,
+            None => return false,
+        };
+        match wire::question(data) {
+            Some((name, rtype, rclass)) => {
+                rtype == qtype && rclass == qclass &&
+                name.eq_ignore_ascii_case(
+                    self.wire_name().trim_right_matches('.'))
+            }
+            None => false,
+        }
+    }
+}
+
+impl DnsMachine {
+    /// Answers the query that is not parsed by `dns_parser`
+    ///
+    /// `Query::Raw` gets all the sections of the response, even if there
+    /// are no answers or the name doesn't exist. CNAME records are
+    /// followed for the typed queries only.
+    pub fn handle_raw<C>(&mut self, req: Request, data: &[u8],
+        name_error: bool, scope: &mut Scope<C>)
+    {
+        let now = scope.now();
+        let sections = match wire::sections(data) {
+            Some(sections) => sections,
+            None => {
+                // Bad packet, but the question is fine. Log it?
+                self.fail(req, Answer::ServerUnavailable, now);
+                return;
+            }
+        };
+        // Minimum TTL of the zone is the last field of SOA
+        let negative_ttl = negative_ttl(sections.nameservers.iter()
+            .filter(|rr| rr.rtype == SOA_TYPE && rr.data.len() >= 4)
+            .map(|rr| (rr.ttl, wire::read_u32(&rr.data, rr.data.len()-4))));
+        let ttl = sections.answers.iter().map(|rr| rr.ttl)
+            .fold(i32::MAX as u32, min);
+        if let Query::Raw { .. } = req.query {
+            // Referrals and negative answers are useful as is
+            let ttl = if name_error || sections.answers.is_empty() {
+                negative_ttl
+            } else {
+                ttl
+            };
+            self.answer(req, Answer::Raw(sections), ttl, now);
+            return;
+        }
+        let aliases = req.aliases.len();
+        let cnames = sections.answers.iter()
+            .filter(|rr| rr.rtype == CNAME_TYPE)
+            .filter_map(|rr| {
+                wire::read_name(&rr.data, 0)
+                .map(|(target, _)| (rr.name.clone(), target))
+            })
+            .collect::<Vec<_>>();
+        let req = match self.follow_cnames(req, &cnames, now) {
+            Some(req) => req,
+            None => return,
+        };
+        let result = match req.query {
+            Query::LookupCaa(_) => Answer::Caa(
+                records(&req, &sections.answers, wire::caa)),
+            Query::LookupTlsa(_) => Answer::Tlsa(
+                records(&req, &sections.answers, wire::tlsa)),
+            Query::LookupSshfp(_) => Answer::Sshfp(
+                records(&req, &sections.answers, wire::sshfp)),
+            _ => unreachable!(),
+        };
+        self.answer_response(req, result, name_error, aliases,
+                             ttl, negative_ttl, scope);
+    }
+}
+
+/// Parses the records of requested type for the queried name
+fn records<T, F>(req: &Request, answers: &[RawRecord], parse: F) -> Vec<T>
+    where F: Fn(&[u8]) -> Option<T>
+{
+    let (rtype, class) = req.query.raw_type().unwrap();
+    let wire_name = req.wire_name();
+    let name = wire_name.trim_right_matches('.');
+    answers.iter()
+        .filter(|rr| rr.rtype == rtype && rr.class == class &&
+                     rr.name.eq_ignore_ascii_case(name))
+        // Bad values are skipped. Log them?
+        .filter_map(|rr| parse(&rr.data))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use {Config, Query, Answer};
+    use mock::{Mock, Server, reply, result};
+    use wire::{self, CAA_TYPE, IN_CLASS};
+    use super::CNAME_TYPE;
+
+    fn resolver(server: &Server) -> Mock {
+        Mock::new(Config::new(vec![server.address()]))
+    }
+
+    #[test]
+    fn caa_alias() {
+        let server = Server::new();
+        let mut mock = resolver(&server);
+        let slot = mock.query(Query::LookupCaa("alias.example.com".into()));
+        let query = server.recv().unwrap();
+        let target = b"\x06target\x07example\x03com\x00";
+        server.send(&reply(&query, &[(CNAME_TYPE, target)]), &mock);
+        mock.ready();
+        assert!(result(&slot).is_none());
+
+        // Nameserver returned only the alias, so its target is queried
+        let query = server.recv().unwrap();
+        assert_eq!(wire::question(&query),
+                   Some(("target.example.com".into(), CAA_TYPE, IN_CLASS)));
+        let caa = b"\x00\x05issueca.example.net";
+        server.send(&reply(&query, &[(CAA_TYPE, caa)]), &mock);
+        mock.ready();
+        match result(&slot) {
+            Some(Answer::Caa(ref records)) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].tag, "issue");
+            }
+            value => panic!("Unexpected answer {:?}", value),
+        }
+    }
+
+    #[test]
+    fn caa_alias_loop() {
+        let server = Server::new();
+        let mut mock = resolver(&server);
+        let slot = mock.query(Query::LookupCaa("alias.example.com".into()));
+        let query = server.recv().unwrap();
+        let target = b"\x05alias\x07example\x03com\x00";
+        server.send(&reply(&query, &[(CNAME_TYPE, target)]), &mock);
+        mock.ready();
+        match result(&slot) {
+            Some(Answer::CnameLoop) => {}
+            value => panic!("Unexpected answer {:?}", value),
+        }
+        assert!(server.recv().is_none());
+    }
+}
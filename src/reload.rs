@@ -0,0 +1,43 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use rotor::Time;
+
+use {DnsMachine};
+use hosts::Hosts;
+
+
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl DnsMachine {
+    /// Re-reads resolv.conf and hosts file if they have been changed
+    ///
+    /// Requests in flight are continued with new nameservers on retry
+    pub fn check_reload(&mut self, now: Time) {
+        match self.next_reload {
+            Some(time) if time <= now => {}
+            _ => return,
+        }
+        self.next_reload = self.config.reload_interval.map(|x| now + x);
+        if let Some(path) = self.config.resolv_conf.clone() {
+            let mtime = modified(&path);
+            if mtime != self.resolv_conf_mtime {
+                self.resolv_conf_mtime = mtime;
+                // Broken file is probably being written right now, so we
+                // keep old settings until the next change
+                self.config.read_resolv_conf(&path).ok();
+            }
+        }
+        if let Some(path) = self.config.hosts_file.clone() {
+            let mtime = modified(&path);
+            if mtime != self.hosts_mtime {
+                self.hosts_mtime = mtime;
+                self.hosts = Hosts::read(&path)
+                    .unwrap_or_else(|_| Hosts::default());
+            }
+        }
+    }
+}
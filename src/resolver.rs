@@ -1,9 +1,13 @@
 use std::io;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use rotor::GenericScope;
 
-use {Query, Resolver, CacheEntry, Request, TimeEntry};
+use {Query, QueryOptions, Resolver, Waiter, Config};
+use {NameserverStats};
+use cancel::Slot;
 
 quick_error! {
     /// Error when creating a query
@@ -29,37 +33,78 @@ quick_error! {
 
 impl Resolver {
     pub fn query<S>(&self, query: Query, scope: &mut GenericScope)
-        -> Result<Arc<Mutex<Option<Arc<CacheEntry>>>>, QueryError>
+        -> Result<Slot, QueryError>
+        where S: GenericScope
+    {
+        self.query_with::<S>(query, QueryOptions::new(), scope)
+    }
+    /// Same as `query` but allows to override settings for this query
+    pub fn query_with<S>(&self, query: Query, options: QueryOptions,
+        scope: &mut GenericScope)
+        -> Result<Slot, QueryError>
         where S: GenericScope
     {
         let ref mut res = *self.0.lock().unwrap();
-        if let Some(cache) =  res.cache.get(&query).map(|x| x.clone()) {
-            if scope.now() > cache.expire {
-                res.cache.remove(&query);
-            } else {
-                // TODO(tailhook) should we trade off possible bugs for
-                //                performance?
-                scope.notifier().wakeup().unwrap();
-                return Ok(Arc::new(Mutex::new(Some(cache.clone()))));
-            }
-        }
-        // TODO(tailhook) implement round-robin/random server selection
-        let server = 0;
-        let id = try!(res.send_request(&query, server));
-
+        res.check_reload(scope.now());
         let result = Arc::new(Mutex::new(None));
-        let deadline = scope.now() + res.config.timeout;
-        res.running.insert(id, Request {
-            id: id,
-            query: query,
-            nameserver_index: server,
-            attempts: 1,
-            server: res.config.nameservers[server],
-            deadline: deadline,
-            notifiers: vec![(result.clone(), scope.notifier())],
-        });
-        res.timeouts.push(TimeEntry(deadline, id));
+        // TODO(tailhook) should we trade off possible bugs for performance
+        //                when answering from cache?
+        try!(res.start_search(query, options,
+            Waiter::Machine(result.clone(), scope.notifier()), scope.now()));
         res.notifier.wakeup().unwrap();  // to schedule a timeout
         Ok(result)
     }
+    /// Cancels the query, the `slot` is what `query` has returned
+    ///
+    /// The result is never put into the slot and the state machine isn't
+    /// woken up after this call. Request is dropped if nobody else
+    /// waits for it.
+    pub fn cancel(&self, slot: &Slot) {
+        self.0.lock().unwrap().cancel(slot);
+    }
+    /// Returns a copy of currently active configuration
+    pub fn config(&self) -> Config {
+        self.0.lock().unwrap().config.clone()
+    }
+    /// Replaces nameservers used for the new requests and for retries
+    ///
+    /// This also disables reloading nameservers from resolv.conf, so
+    /// they are not overwritten when file changes.
+    ///
+    /// # Panics
+    ///
+    /// When the list is empty
+    pub fn set_nameservers(&self, nameservers: Vec<SocketAddr>) {
+        assert!(nameservers.len() > 0);
+        let ref mut res = *self.0.lock().unwrap();
+        res.config.nameservers = nameservers;
+        res.config.resolv_conf = None;
+    }
+    /// Sets timeout of the first round of attempts
+    ///
+    /// It's doubled on each next round through the nameserver list, up to
+    /// 30 seconds (or this timeout, if it's larger).
+    ///
+    /// Note: it's overwritten when resolv.conf is reloaded, see
+    /// `set_nameservers`
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.0.lock().unwrap().config.timeout = timeout;
+    }
+    /// Sets number of attempts made before giving up on the request
+    ///
+    /// Note: it's overwritten when resolv.conf is reloaded, see
+    /// `set_nameservers`
+    pub fn set_attempts(&self, attempts: u32) {
+        self.0.lock().unwrap().config.attempts = attempts;
+    }
+    /// Returns statistics of nameservers that were queried so far
+    pub fn nameserver_stats(&self) -> Vec<NameserverStats> {
+        self.0.lock().unwrap().servers.values().cloned().collect()
+    }
+    /// Removes all cached answers
+    ///
+    /// Requests in flight are not affected
+    pub fn flush_cache(&self) {
+        self.0.lock().unwrap().cache.clear();
+    }
 }
@@ -0,0 +1,95 @@
+use std::cmp::{min, max};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use {DnsMachine};
+use config::ServerSelection;
+
+/// Upper bound for the smoothed RTT of a failing nameserver
+const MAX_SRTT: u64 = 30;
+
+
+/// Statistics of a single nameserver
+#[derive(Debug, Clone)]
+pub struct NameserverStats {
+    pub address: SocketAddr,
+    /// Smoothed round trip time, `None` if server never responded
+    pub srtt: Option<Duration>,
+    /// Number of failures since the last successful response
+    pub failures: u32,
+}
+
+impl DnsMachine {
+    /// Chooses the nameserver for the request
+    ///
+    /// The `previous` is the index of nameserver used for the previous
+    /// attempt of the same request
+    pub fn select_server(&mut self, previous: Option<usize>) -> usize {
+        let len = self.config.nameservers.len();
+        match (self.config.selection, previous) {
+            (ServerSelection::Sequential, None) => 0,
+            (ServerSelection::Rotate, None) => {
+                self.rotation = self.rotation.wrapping_add(1);
+                self.rotation % len
+            }
+            (ServerSelection::LowestRtt, _) if len > 1 => {
+                self.lowest_rtt(previous)
+            }
+            (_, Some(idx)) => (idx + 1) % len,
+            (_, None) => 0,
+        }
+    }
+    /// Chooses the server with the lowest smoothed RTT like BIND does
+    ///
+    /// Servers that never responded are tried first. Smoothed RTT of the
+    /// servers not chosen is decreased a little, so slow or failed servers
+    /// are retried from time to time.
+    fn lowest_rtt(&mut self, exclude: Option<usize>) -> usize {
+        let mut best = None;
+        for (idx, addr) in self.config.nameservers.iter().enumerate() {
+            if Some(idx) == exclude {
+                continue;
+            }
+            let srtt = self.servers.get(addr)
+                .and_then(|x| x.srtt)
+                .unwrap_or(Duration::new(0, 0));
+            match best {
+                Some((_, best_srtt)) if best_srtt <= srtt => {}
+                _ => best = Some((idx, srtt)),
+            }
+        }
+        let idx = best.map(|(idx, _)| idx).unwrap_or(0);
+        let chosen = self.config.nameservers[idx];
+        for (addr, stats) in self.servers.iter_mut() {
+            if *addr != chosen {
+                stats.srtt = stats.srtt.map(|x| x - x / 32);
+            }
+        }
+        return idx;
+    }
+    /// Updates statistics when nameserver responds to a request
+    pub fn server_responded(&mut self, addr: SocketAddr, sent: Instant) {
+        let rtt = sent.elapsed();
+        let stats = self.stats_mut(addr);
+        stats.srtt = Some(match stats.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        stats.failures = 0;
+    }
+    /// Updates statistics when request to the nameserver fails
+    pub fn server_failed(&mut self, addr: SocketAddr) {
+        let timeout = self.config.timeout;
+        let stats = self.stats_mut(addr);
+        stats.failures += 1;
+        stats.srtt = Some(min(max(stats.srtt.unwrap_or(timeout) * 2, timeout),
+                              Duration::new(MAX_SRTT, 0)));
+    }
+    fn stats_mut(&mut self, addr: SocketAddr) -> &mut NameserverStats {
+        self.servers.entry(addr).or_insert_with(|| NameserverStats {
+            address: addr,
+            srtt: None,
+            failures: 0,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::SocketAddr;
+use std::io::ErrorKind::Interrupted;
+
+use rotor::mio::{TryRead, TryWrite};
+use rotor::mio::tcp::TcpStream;
+
+
+/// Result of making progress on the connection
+pub enum Progress {
+    /// Nothing to do until the next readiness event
+    Wait,
+    /// Full response received (without the length prefix)
+    Done(Vec<u8>),
+    /// Connection is closed or broken before the response is received
+    Failed,
+}
+
+/// A single query sent over TCP
+///
+/// Each message is prefixed by a two byte length field
+/// (RFC 1035, section 4.2.2). We open a connection per query and close it
+/// as soon as the response is received.
+pub struct Connection {
+    sock: TcpStream,
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl Connection {
+    pub fn connect(addr: &SocketAddr, packet: &[u8])
+        -> io::Result<Connection>
+    {
+        let mut output = Vec::with_capacity(packet.len() + 2);
+        output.push((packet.len() >> 8) as u8);
+        output.push(packet.len() as u8);
+        output.extend(packet);
+        Ok(Connection {
+            sock: try!(TcpStream::connect(addr)),
+            output: output,
+            input: Vec::new(),
+        })
+    }
+    pub fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+    /// Writes as much of the query as possible and reads the response
+    ///
+    /// Must be called on every readiness event, as we can't know which
+    /// socket of the state machine the event is for.
+    pub fn poll(&mut self) -> Progress {
+        while self.output.len() > 0 {
+            match self.sock.try_write(&self.output) {
+                Ok(Some(0)) => return Progress::Failed,
+                Ok(Some(bytes)) => {
+                    self.output.drain(..bytes);
+                }
+                Ok(None) => return Progress::Wait,
+                Err(ref e) if e.kind() == Interrupted => continue,
+                Err(_) => return Progress::Failed,
+            }
+        }
+        let mut eof = false;
+        loop {
+            let mut buf = [0u8; 4096];
+            match self.sock.try_read(&mut buf) {
+                Ok(Some(0)) => {
+                    eof = true;
+                    break;
+                }
+                Ok(Some(bytes)) => {
+                    self.input.extend(&buf[..bytes]);
+                }
+                Ok(None) => break,
+                Err(ref e) if e.kind() == Interrupted => continue,
+                Err(_) => return Progress::Failed,
+            }
+        }
+        if self.input.len() >= 2 {
+            let len = ((self.input[0] as usize) << 8)
+                      | self.input[1] as usize;
+            if self.input.len() >= len + 2 {
+                return Progress::Done(self.input[2..len+2].to_vec());
+            }
+        }
+        if eof {
+            Progress::Failed
+        } else {
+            Progress::Wait
+        }
+    }
+}
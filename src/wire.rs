@@ -0,0 +1,490 @@
+//! Low-level helpers for things `dns_parser` doesn't support yet
+
+use dns_parser::ResponseCode;
+
+use {RawRecord, RawAnswer, CaaRecord, TlsaRecord, SshfpRecord};
+
+pub const HEADER_SIZE: usize = 12;
+/// The TC bit in the third byte of the header
+pub const TRUNCATED_FLAG: u8 = 0x02;
+const OPT_TYPE: u16 = 41;
+pub const TXT_TYPE: u16 = 16;
+pub const SSHFP_TYPE: u16 = 44;
+pub const TLSA_TYPE: u16 = 52;
+pub const CAA_TYPE: u16 = 257;
+pub const IN_CLASS: u16 = 1;
+
+macro_rules! try_opt {
+    ($e:expr) => {
+        match $e {
+            Some(x) => x,
+            None => return None,
+        }
+    }
+}
+
+
+pub fn read_u16(data: &[u8], pos: usize) -> u16 {
+    ((data[pos] as u16) << 8) | data[pos+1] as u16
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> u32 {
+    ((read_u16(data, pos) as u32) << 16) | read_u16(data, pos+2) as u32
+}
+
+fn write_u16(data: &mut [u8], pos: usize, value: u16) {
+    data[pos] = (value >> 8) as u8;
+    data[pos+1] = value as u8;
+}
+
+/// Returns position right after the (possibly compressed) name
+pub fn skip_name(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        if pos >= data.len() {
+            return None;
+        }
+        let len = data[pos] as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        } else if len & 0xC0 == 0xC0 {
+            // Pointer is always the last label
+            return if pos + 2 <= data.len() { Some(pos + 2) } else { None };
+        }
+        pos += len + 1;
+    }
+}
+
+/// Reads (possibly compressed) name, returns it and the position right
+/// after it
+pub fn read_name(data: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut cur = pos;
+    let mut end = None;
+    // Number of pointers followed, to stop on compression loops
+    let mut jumps = 0;
+    loop {
+        if cur >= data.len() {
+            return None;
+        }
+        let len = data[cur] as usize;
+        if len == 0 {
+            return Some((name, end.unwrap_or(cur + 1)));
+        } else if len & 0xC0 == 0xC0 {
+            if cur + 2 > data.len() || jumps > data.len() / 2 {
+                return None;
+            }
+            end = end.or(Some(cur + 2));
+            cur = (read_u16(data, cur) & 0x3FFF) as usize;
+            jumps += 1;
+            continue;
+        }
+        if cur + len + 1 > data.len() {
+            return None;
+        }
+        if name.len() > 0 {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(&data[cur+1..cur+len+1]));
+        cur += len + 1;
+    }
+}
+
+/// Appends the name at `pos` to `out` without compression, returns the
+/// position right after the name in `data`
+fn copy_name(data: &[u8], pos: usize, out: &mut Vec<u8>) -> Option<usize> {
+    let mut cur = pos;
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        if cur >= data.len() {
+            return None;
+        }
+        let len = data[cur] as usize;
+        if len == 0 {
+            out.push(0);
+            return Some(end.unwrap_or(cur + 1));
+        } else if len & 0xC0 == 0xC0 {
+            if cur + 2 > data.len() || jumps > data.len() / 2 {
+                return None;
+            }
+            end = end.or(Some(cur + 2));
+            cur = (read_u16(data, cur) & 0x3FFF) as usize;
+            jumps += 1;
+            continue;
+        }
+        if cur + len + 1 > data.len() {
+            return None;
+        }
+        out.extend(&data[cur..cur+len+1]);
+        cur += len + 1;
+    }
+}
+
+/// Returns record data with names decompressed
+///
+/// Only types of RFC 1035 may have compressed names (RFC 3597, section 4)
+fn record_data(data: &[u8], rtype: u16, pos: usize, end: usize)
+    -> Option<Vec<u8>>
+{
+    // Number of bytes before the names and number of names
+    let (prefix, names) = match rtype {
+        2 | 3 | 4 | 5 | 7 | 8 | 9 | 12 => (0, 1),  // NS, CNAME, PTR...
+        6 | 14 => (0, 2),  // SOA, MINFO
+        15 => (2, 1),  // MX
+        _ => return Some(data[pos..end].to_vec()),
+    };
+    if pos + prefix > end {
+        return None;
+    }
+    let mut result = data[pos..pos+prefix].to_vec();
+    let mut cur = pos + prefix;
+    for _ in 0..names {
+        cur = try_opt!(copy_name(&data[..end], cur, &mut result));
+    }
+    result.extend(&data[cur..end]);
+    Some(result)
+}
+
+/// Parses records of the section starting at `pos`
+fn read_records(data: &[u8], mut pos: usize, count: u16,
+    result: &mut Vec<RawRecord>)
+    -> Option<usize>
+{
+    for _ in 0..count {
+        let end = try_opt!(skip_record(data, pos));
+        let (name, pos_) = try_opt!(read_name(data, pos));
+        let rtype = read_u16(data, pos_);
+        result.push(RawRecord {
+            name: name,
+            rtype: rtype,
+            class: read_u16(data, pos_ + 2),
+            ttl: read_u32(data, pos_ + 4),
+            data: try_opt!(record_data(data, rtype, pos_ + 10, end)),
+        });
+        pos = end;
+    }
+    Some(pos)
+}
+
+/// Returns all the records of the response
+///
+/// Returns `None` if packet is malformed
+pub fn sections(data: &[u8]) -> Option<RawAnswer> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+    let questions = read_u16(data, 4);
+    let mut pos = HEADER_SIZE;
+    for _ in 0..questions {
+        pos = try_opt!(skip_name(data, pos)) + 4;
+    }
+    let mut result = RawAnswer {
+        response_code: response_code(data),
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        additional: Vec::new(),
+    };
+    pos = try_opt!(read_records(data, pos, read_u16(data, 6),
+                                &mut result.answers));
+    pos = try_opt!(read_records(data, pos, read_u16(data, 8),
+                                &mut result.nameservers));
+    try_opt!(read_records(data, pos, read_u16(data, 10),
+                          &mut result.additional));
+    Some(result)
+}
+
+/// Returns the only question of the response: name, type and class
+pub fn question(data: &[u8]) -> Option<(String, u16, u16)> {
+    if data.len() < HEADER_SIZE || read_u16(data, 4) != 1 {
+        return None;
+    }
+    let (name, pos) = try_opt!(read_name(data, HEADER_SIZE));
+    if pos + 4 > data.len() {
+        return None;
+    }
+    Some((name, read_u16(data, pos), read_u16(data, pos + 2)))
+}
+
+/// Returns the response code from the header
+pub fn response_code(data: &[u8]) -> ResponseCode {
+    ResponseCode::from(data[3] & 0x0F)
+}
+
+/// Builds the query for any type and class, `None` if name is invalid
+pub fn build_query(id: u16, recursion: bool, name: &str, qtype: u16,
+    qclass: u16)
+    -> Option<Vec<u8>>
+{
+    let mut pack = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    pack.extend(&[(id >> 8) as u8, id as u8]);
+    pack.extend(&[if recursion { 0x01 } else { 0x00 }, 0]);
+    pack.extend(&[0, 1, 0, 0, 0, 0, 0, 0]);  // one question
+    let name = name.trim_right_matches('.');
+    // Empty name is the root domain
+    if name.len() > 0 {
+        for label in name.split('.') {
+            if label.len() == 0 || label.len() > 63 {
+                return None;
+            }
+            pack.push(label.len() as u8);
+            pack.extend(label.as_bytes());
+        }
+    }
+    pack.push(0);
+    if pack.len() - HEADER_SIZE > 255 {
+        return None;
+    }
+    pack.extend(&[(qtype >> 8) as u8, qtype as u8]);
+    pack.extend(&[(qclass >> 8) as u8, qclass as u8]);
+    Some(pack)
+}
+
+/// Splits data of the TXT record into character strings
+/// (RFC 1035, section 3.3.14)
+pub fn character_strings(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let end = pos + 1 + data[pos] as usize;
+        if end > data.len() {
+            return None;
+        }
+        result.push(data[pos+1..end].to_vec());
+        pos = end;
+    }
+    Some(result)
+}
+
+/// Parses data of the CAA record (RFC 8659, section 4.1)
+pub fn caa(data: &[u8]) -> Option<CaaRecord> {
+    if data.len() < 2 || data.len() < 2 + data[1] as usize {
+        return None;
+    }
+    let end = 2 + data[1] as usize;
+    Some(CaaRecord {
+        flags: data[0],
+        tag: String::from_utf8_lossy(&data[2..end]).into_owned(),
+        value: data[end..].to_vec(),
+    })
+}
+
+/// Parses data of the TLSA record (RFC 6698, section 2.1)
+pub fn tlsa(data: &[u8]) -> Option<TlsaRecord> {
+    if data.len() < 3 {
+        return None;
+    }
+    Some(TlsaRecord {
+        usage: data[0],
+        selector: data[1],
+        matching_type: data[2],
+        data: data[3..].to_vec(),
+    })
+}
+
+/// Parses data of the SSHFP record (RFC 4255, section 3.1)
+pub fn sshfp(data: &[u8]) -> Option<SshfpRecord> {
+    if data.len() < 2 {
+        return None;
+    }
+    Some(SshfpRecord {
+        algorithm: data[0],
+        fingerprint_type: data[1],
+        fingerprint: data[2..].to_vec(),
+    })
+}
+
+/// Returns position right after the resource record
+fn skip_record(data: &[u8], pos: usize) -> Option<usize> {
+    let pos = try_opt!(skip_name(data, pos));
+    if pos + 10 > data.len() {
+        return None;
+    }
+    let end = pos + 10 + read_u16(data, pos + 8) as usize;
+    if end > data.len() {
+        return None;
+    }
+    Some(end)
+}
+
+/// Appends OPT pseudo-record to the query (RFC 6891)
+pub fn add_opt(pack: &mut Vec<u8>, payload_size: u16) {
+    let additional = read_u16(pack, 10);
+    write_u16(pack, 10, additional + 1);
+    pack.push(0);  // root domain
+    pack.extend(&[(OPT_TYPE >> 8) as u8, OPT_TYPE as u8]);
+    // class field is a payload size
+    pack.extend(&[(payload_size >> 8) as u8, payload_size as u8]);
+    // extended rcode, version and flags are zero, no options
+    pack.extend(&[0, 0, 0, 0, 0, 0]);
+}
+
+/// Removes OPT pseudo-record from the response, so it can be parsed
+///
+/// Returns `None` if packet is malformed
+pub fn strip_opt(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+    let questions = read_u16(data, 4);
+    let records = read_u16(data, 6) as usize
+                + read_u16(data, 8) as usize;
+    let additional = read_u16(data, 10);
+    let mut pos = HEADER_SIZE;
+    for _ in 0..questions {
+        pos = try_opt!(skip_name(data, pos)) + 4;
+    }
+    if pos > data.len() {
+        return None;
+    }
+    for _ in 0..records {
+        pos = try_opt!(skip_record(data, pos));
+    }
+    let mut result = data[..pos].to_vec();
+    let mut left = additional;
+    for _ in 0..additional {
+        let end = try_opt!(skip_record(data, pos));
+        let name_end = try_opt!(skip_name(data, pos));
+        if read_u16(data, name_end) == OPT_TYPE {
+            left -= 1;
+        } else {
+            result.extend(&data[pos..end]);
+        }
+        pos = end;
+    }
+    write_u16(&mut result, 10, left);
+    Some(result)
+}
+
+#[cfg(test)]
+mod test {
+    use dns_parser::ResponseCode;
+    use super::{sections, read_name, build_query, caa, tlsa, sshfp};
+
+    /// Referral to `ns1.example.com` for the NS query of `example.com`
+    fn referral() -> Vec<u8> {
+        let mut pack = vec![
+            0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 1, 0, 1,
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0, 2, 0, 1,
+        ];
+        // Authority section at 29, rdata at 41
+        pack.extend(&[0xC0, 12, 0, 2, 0, 1, 0, 0, 0x0E, 0x10, 0, 6]);
+        pack.extend(&[3, b'n', b's', b'1', 0xC0, 12]);
+        // Additional section at 47
+        pack.extend(&[0xC0, 41, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4]);
+        pack.extend(&[192, 0, 2, 1]);
+        pack
+    }
+
+    #[test]
+    fn read_compressed_name() {
+        let pack = referral();
+        assert_eq!(read_name(&pack, 12), Some(("example.com".into(), 25)));
+        assert_eq!(read_name(&pack, 41),
+                   Some(("ns1.example.com".into(), 47)));
+        assert_eq!(read_name(&pack, 47),
+                   Some(("ns1.example.com".into(), 49)));
+    }
+
+    #[test]
+    fn read_bad_name() {
+        // Pointer to itself
+        assert_eq!(read_name(&[0xC0, 0], 0), None);
+        assert_eq!(read_name(&[3, b'c', b'o'], 0), None);
+        assert_eq!(read_name(&[0xC0], 0), None);
+    }
+
+    #[test]
+    fn referral_sections() {
+        let sections = sections(&referral()).unwrap();
+        match sections.response_code {
+            ResponseCode::NoError => {}
+            code => panic!("unexpected response code {:?}", code),
+        }
+        assert_eq!(sections.answers.len(), 0);
+        assert_eq!(sections.nameservers.len(), 1);
+        let ns = &sections.nameservers[0];
+        assert_eq!(ns.name, "example.com");
+        assert_eq!((ns.rtype, ns.class, ns.ttl), (2, 1, 3600));
+        // The name is decompressed
+        assert_eq!(ns.data, b"\x03ns1\x07example\x03com\x00".to_vec());
+        assert_eq!(sections.additional.len(), 1);
+        let glue = &sections.additional[0];
+        assert_eq!(glue.name, "ns1.example.com");
+        assert_eq!((glue.rtype, glue.class), (1, 1));
+        assert_eq!(glue.data, vec![192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn name_error_sections() {
+        let mut pack = referral();
+        pack[3] = 0x83;
+        match sections(&pack).unwrap().response_code {
+            ResponseCode::NameError => {}
+            code => panic!("unexpected response code {:?}", code),
+        }
+    }
+
+    #[test]
+    fn truncated_sections() {
+        let pack = referral();
+        assert!(sections(&pack[..pack.len()-1]).is_none());
+        assert!(sections(&pack[..40]).is_none());
+        assert!(sections(&pack[..8]).is_none());
+    }
+
+    #[test]
+    fn query() {
+        let pack = build_query(0x1234, true, "example.com.", 257, 1);
+        assert_eq!(pack, Some(vec![
+            0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0x01, 0x01, 0, 1,
+        ]));
+    }
+
+    #[test]
+    fn root_query() {
+        assert_eq!(build_query(1, false, ".", 2, 1), Some(vec![
+            0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1]));
+    }
+
+    #[test]
+    fn bad_query_name() {
+        assert_eq!(build_query(1, true, "a..b", 1, 1), None);
+        let label = ::std::iter::repeat("a").take(64).collect::<String>();
+        assert_eq!(build_query(1, true, &label, 1, 1), None);
+        let long = ::std::iter::repeat("abc.").take(64).collect::<String>();
+        assert_eq!(build_query(1, true, &long, 1, 1), None);
+    }
+
+    #[test]
+    fn caa_record() {
+        let rec = caa(b"\x80\x05issueca.example.net").unwrap();
+        assert_eq!(rec.flags, 0x80);
+        assert_eq!(rec.tag, "issue");
+        assert_eq!(rec.value, b"ca.example.net".to_vec());
+        let rec = caa(b"\x00\x05iodef").unwrap();
+        assert_eq!(rec.tag, "iodef");
+        assert_eq!(rec.value, Vec::<u8>::new());
+        assert!(caa(b"\x00\x06iodef").is_none());
+        assert!(caa(b"\x00").is_none());
+    }
+
+    #[test]
+    fn tlsa_record() {
+        let rec = tlsa(&[3, 1, 1, 0xAB, 0xCD]).unwrap();
+        assert_eq!((rec.usage, rec.selector, rec.matching_type), (3, 1, 1));
+        assert_eq!(rec.data, vec![0xAB, 0xCD]);
+        assert_eq!(tlsa(&[3, 1, 1]).unwrap().data, Vec::<u8>::new());
+        assert!(tlsa(&[3, 1]).is_none());
+    }
+
+    #[test]
+    fn sshfp_record() {
+        let rec = sshfp(&[4, 2, 0x12, 0x34]).unwrap();
+        assert_eq!((rec.algorithm, rec.fingerprint_type), (4, 2));
+        assert_eq!(rec.fingerprint, vec![0x12, 0x34]);
+        assert!(sshfp(&[4]).is_none());
+    }
+}
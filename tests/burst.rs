@@ -0,0 +1,72 @@
+#[macro_use] extern crate rotor;
+extern crate rotor_dns;
+extern crate rotor_tools;
+
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use rotor::void::{Void, unreachable};
+use rotor::{Machine, EventSet, Scope, Response};
+use rotor_dns::Query;
+use rotor_tools::loop_ext::LoopExt;
+
+struct Idle;
+struct Context;
+
+rotor_compose!(enum Composed/CSeed <Context> {
+    Idle(Idle),
+    Dns(rotor_dns::Fsm<Context>),
+});
+
+impl Machine for Idle {
+    type Seed = Void;
+    type Context = Context;
+    fn create(seed: Self::Seed, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    { unreachable(seed); }
+    fn ready(self, _events: EventSet, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    { Response::ok(self) }
+    fn spawned(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    { Response::ok(self) }
+    fn timeout(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    { Response::ok(self) }
+    fn wakeup(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    { Response::ok(self) }
+}
+
+#[test]
+fn burst_of_identical_queries_sends_one_datagram() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut cfg = rotor_dns::Config::new(vec![server.local_addr().unwrap()]);
+    // No retries while the test is waiting for datagrams
+    cfg.timeout = Duration::new(60, 0);
+    cfg.attempts = 1;
+    // The loop never finishes, it's killed when the test exits
+    thread::spawn(move || {
+        let mut creator = rotor::Loop::new(&rotor::Config::new()).unwrap();
+        let resolver = creator.add_and_fetch(Composed::Dns, |scope| {
+            rotor_dns::create_resolver(scope, cfg)
+        }).unwrap();
+        let mut inst = creator.instantiate(Context);
+        inst.add_machine_with(|scope| {
+            for _ in 0..1000 {
+                resolver.query::<Scope<Context>>(
+                    Query::LookupIpv4("example.com.".into()), scope)
+                    .unwrap();
+            }
+            Response::ok(Composed::Idle(Idle))
+        }).unwrap();
+        inst.run().unwrap();
+    });
+    let mut buf = [0u8; 4096];
+    server.set_read_timeout(Some(Duration::new(10, 0))).unwrap();
+    server.recv_from(&mut buf).unwrap();
+    server.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+    assert!(server.recv_from(&mut buf).is_err(),
+        "more than one datagram is sent");
+}